@@ -9,12 +9,16 @@
 //!
 //! Basic usage:
 //!
-//! ```
+//! ```no_run
+//! # use std::time::Duration;
+//! # use diffusion_to::prelude::*;
+//! # struct Args { api_key: String, prompt: String, steps: u16, model: String }
+//! # async fn run(args: Args) -> diffusion_to::Result<()> {
 //! let client = DiffusionClient::new(args.api_key)?;
 //!
 //! let request = ImageRequest::new(args.prompt)
 //!     .update_steps(args.steps.try_into()?)
-//!     .update_model(args.model.try_into()?)
+//!     .update_model(args.model.try_into()?);
 //! let token = client.request_image(request).await?;
 //!
 //! // wait for up to five minutes
@@ -22,26 +26,84 @@
 //!     .check_and_wait(token, Some(Duration::from_secs(300)))
 //!     .await?;
 //!
-//! println!("{}", iamge.raw)
+//! println!("{}", image.raw);
+//! # Ok(())
+//! # }
 //! ```
 
+use base64::{
+    alphabet,
+    engine::{general_purpose, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use futures::{
+    channel::mpsc,
+    future::{select_ok, AbortRegistration, Abortable, BoxFuture},
+    stream, AsyncWrite, AsyncWriteExt, StreamExt, TryStreamExt,
+};
 use futures_timer::Delay;
-use reqwest::{header, Client, StatusCode};
-use serde::{Deserialize, Serialize};
+use reqwest::{
+    header::{self, HeaderMap},
+    Client, Proxy, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_repr::*;
 use std::{
+    borrow::Cow,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    convert::Infallible,
     fmt::Display,
+    hash::BuildHasher,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
     time::{Duration, Instant},
 };
 use thiserror::Error;
 
-const API_URL: &'static str = "https://diffusion.to/api/image";
-const STATUS_URL: &'static str = "https://diffusion.to/api/image/status";
+const BASE_URL: &str = "https://diffusion.to";
+const IMAGE_PATH: &str = "/api/image";
+const STATUS_PATH: &str = "/api/image/status";
+/// How many base64 characters are decoded at a time when streaming an image to a writer
+const DECODE_CHUNK_LEN: usize = 64 * 1024;
+/// The JPEG quality used when re-encoding without an explicit quality
+#[cfg(feature = "image")]
+const DEFAULT_JPEG_QUALITY: u8 = 80;
+/// How many status checks run at once when checking tokens in bulk
+const BULK_STATUS_CONCURRENCY: usize = 8;
+/// How many completions are kept per parameter set when tracking wait times
+const WAIT_HISTORY_LEN: usize = 20;
+/// utxo-suggested poll duration is five seconds
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How many times a status response that was cut off is retried beyond the configured retries
+const INCOMPLETE_RESPONSE_RETRIES: u32 = 2;
+/// How long to wait before the first retry of a failed poll when no delay is configured
+const DEFAULT_POLL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Large enough for the biggest images the API produces, but still bounded
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Decodes the image payloads, tolerating missing padding and trailing bits
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_allow_trailing_bits(true)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Re-exports of the HTTP types used in the public API, so they can be used without
+/// depending on the exact version of reqwest this crate uses
+pub mod http {
+    pub use reqwest::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        Proxy, StatusCode,
+    };
+}
 
 pub mod prelude {
     pub use super::{
-        DiffusionClient, DiffusionError, DiffusionImage, ImageModel, ImageOrientation,
-        ImageRequest, ImageSize, ImageSteps, ImageToken,
+        Base64Variant, Clock, Config, DiffusionClient, DiffusionClientBuilder, DiffusionError,
+        DiffusionImage, DiffusionImageMeta, ImageModel, ImageOrientation, ImageRequest, ImageSize,
+        ImageStatus, ImageSteps, ImageToken, MemoryTokenStore, PollConfig, PollProgress,
+        SystemClock, Timings, TokenStore, WaitOptions,
     };
 }
 
@@ -75,281 +137,1730 @@ pub enum DiffusionError {
     /// Invalid orientation given
     #[error("invalid orientation")]
     InvalidOrientation,
+    /// The prompt is empty or only whitespace
+    #[error("prompt must not be empty")]
+    EmptyPrompt,
+    /// The prompt contains a term on the client's blocklist
+    #[error("prompt contains the blocked term \"{term}\"")]
+    BlockedContent { term: String },
+    /// The image data is not valid base64
+    #[error(transparent)]
+    InvalidBase64(#[from] base64::DecodeError),
+    /// The image data is valid base64, but not canonically encoded
+    #[error("the image data is not canonical base64")]
+    NonCanonicalBase64,
+    /// The image data is not in a recognized image format
+    #[error("unknown image format")]
+    UnknownImageFormat,
+    /// The response body could not be deserialized
+    #[error(transparent)]
+    InvalidJson(#[from] serde_json::Error),
+    /// The response body was larger than the configured maximum
+    #[error("response body too large")]
+    ResponseTooLarge,
+    /// The connection was lost partway through reading the response body
+    #[error("response body was cut off")]
+    IncompleteResponse,
+    /// No tokens were given to wait on
+    #[error("no tokens given")]
+    NoTokens,
+    /// The token is older than the configured maximum token age
+    #[error("the token has expired")]
+    TokenExpired,
+    /// A single call to the API took longer than its timeout
+    #[error("the request timed out")]
+    Timeout,
+    /// The image data is truncated or otherwise malformed
+    #[error("the image data is corrupt")]
+    CorruptImage,
+    /// The API doesn't know the token, or it is no longer valid
+    #[error("the token was not found")]
+    TokenNotFound,
+    /// The client configuration is invalid, listing every problem found
+    #[error("invalid config: {}", .0.join(", "))]
+    InvalidConfig(Vec<String>),
+    /// Waiting on the image was cancelled. The image is still being generated and the
+    /// token can be used to pick it back up later.
+    #[error("waiting was cancelled")]
+    Cancelled(ImageToken),
+    /// Errors writing the image out
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Errors decoding or encoding the image locally
+    #[cfg(feature = "image")]
+    #[error(transparent)]
+    ImageError(#[from] image::ImageError),
+    /// Invalid quality given
+    #[error("quality must be between 1 and 100")]
+    InvalidQuality,
+    /// A quality was given for a lossless format
+    #[error("quality only applies to lossy formats")]
+    QualityWithLosslessFormat,
+    /// Errors returned from a [`TokenStore`] implementation
+    #[error("token store error")]
+    TokenStoreError(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The image was requested, but saving its token to the [`TokenStore`] failed. The
+    /// image is still being generated and the token can be used to wait on it.
+    #[error("the image was requested but its token could not be saved")]
+    TokenNotSaved {
+        token: ImageToken,
+        #[source]
+        source: Box<DiffusionError>,
+    },
+}
+
+impl DiffusionError {
+    /// A sensible HTTP status code for a web service to respond with when a call to the
+    /// API fails with this error
+    pub fn suggested_status(&self) -> u16 {
+        let upstream = |code: StatusCode| match code {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {
+                code.as_u16()
+            }
+            _ => 502,
+        };
+
+        match self {
+            Self::ReqwestError(e) if e.is_timeout() => 504,
+            Self::ReqwestError(e) => e.status().map_or(502, upstream),
+            Self::UnknownHttpError(code) => upstream(*code),
+            Self::ImageStatusNotReady => 202,
+            Self::Cancelled(_) => 499,
+            Self::TokenExpired => 410,
+            Self::TokenNotFound => 404,
+            Self::TimeExpired | Self::Timeout => 504,
+            Self::InvalidStepAmount
+            | Self::InvalidModel
+            | Self::InvalidSize
+            | Self::InvalidOrientation
+            | Self::EmptyPrompt
+            | Self::BlockedContent { .. }
+            | Self::NoTokens
+            | Self::InvalidQuality
+            | Self::QualityWithLosslessFormat => 400,
+            Self::InvalidHeader(_)
+            | Self::InvalidConfig(_)
+            | Self::Io(_)
+            | Self::TokenStoreError(_)
+            | Self::TokenNotSaved { .. } => 500,
+            #[cfg(feature = "image")]
+            Self::ImageError(_) => 500,
+            Self::InvalidBase64(_)
+            | Self::NonCanonicalBase64
+            | Self::UnknownImageFormat
+            | Self::CorruptImage
+            | Self::InvalidJson(_)
+            | Self::ResponseTooLarge
+            | Self::IncompleteResponse => 502,
+        }
+    }
+
+    /// Whether the error is likely to go away if the call is retried
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::ReqwestError(e) => e.is_connect() || e.is_timeout(),
+            Self::UnknownHttpError(code) => code.is_server_error(),
+            Self::Timeout | Self::IncompleteResponse => true,
+            _ => false,
+        }
+    }
+
+    /// A short description of the error that is safe to show to end users, without any
+    /// internal details
+    pub fn user_message(&self) -> &'static str {
+        match self.suggested_status() {
+            202 => "The image is still being generated.",
+            400 => "The image request is invalid.",
+            401 | 403 => "The image service rejected the credentials.",
+            404 => "The image request could not be found.",
+            410 => "The image request has expired.",
+            429 => "Too many image requests. Please try again later.",
+            499 => "The image request was cancelled.",
+            504 => "The image took too long to generate.",
+            _ => "The image service is unavailable right now.",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DiffusionError>;
 
+type WaitHistory = HashMap<(ImageModel, ImageSteps, ImageSize), VecDeque<Duration>>;
+
 /// The client used to interact with the diffusion.to API
+#[derive(Clone)]
 pub struct DiffusionClient {
     api: Client,
+    key: header::HeaderValue,
+    max_response_bytes: usize,
+    max_token_age: Option<Duration>,
+    wait_history: Option<Arc<Mutex<WaitHistory>>>,
+    submit_timeout: Option<Duration>,
+    status_timeout: Option<Duration>,
+    token_store: Option<Arc<dyn TokenStore>>,
+    content_type: header::HeaderValue,
+    clock: Arc<dyn Clock>,
+    capture_raw_responses: bool,
+    base_url: String,
+    poll_config: PollConfig,
+    blocklist: Arc<[String]>,
+    request_defaults: Option<ImageRequest>,
 }
 
 impl DiffusionClient {
     pub fn new(key: String) -> Result<Self> {
-        let mut headers = header::HeaderMap::new();
+        Self::builder(key).build()
+    }
+
+    /// Build a client from a [`Config`], such as one deserialized from a configuration
+    /// file. The whole config is validated up front, and every problem found is returned
+    /// together in [`DiffusionError::InvalidConfig`].
+    pub fn from_config(config: Config) -> Result<Self> {
+        let mut problems = Vec::new();
+        let mut duration = |name: &str, secs: Option<f64>| {
+            secs.and_then(|secs| match Duration::try_from_secs_f64(secs) {
+                Ok(duration) => Some(duration),
+                Err(_) => {
+                    problems.push(format!("{} must be a non-negative number of seconds", name));
+                    None
+                }
+            })
+        };
+        let timeout = duration("timeout_secs", config.timeout_secs);
+        let submit_timeout = duration("submit_timeout_secs", config.submit_timeout_secs);
+        let status_timeout = duration("status_timeout_secs", config.status_timeout_secs);
+        let max_token_age = duration("max_token_age_secs", config.max_token_age_secs);
+        let poll_interval = duration("poll_interval_secs", config.poll_interval_secs);
+        let poll_max_interval = duration("poll_max_interval_secs", config.poll_max_interval_secs);
+        let poll_retry_delay = duration("poll_retry_delay_secs", config.poll_retry_delay_secs);
+        let poll_initial_delay =
+            duration("poll_initial_delay_secs", config.poll_initial_delay_secs);
+        let poll_initial_jitter =
+            duration("poll_initial_jitter_secs", config.poll_initial_jitter_secs);
+
+        if config.api_key.trim().is_empty() {
+            problems.push("api_key must not be empty".to_string());
+        }
+        if poll_interval.is_some_and(|interval| interval.is_zero()) {
+            problems.push("poll_interval_secs must be greater than zero".to_string());
+        }
+        if let Some(base_url) = &config.base_url {
+            if reqwest::Url::parse(base_url).is_err() {
+                problems.push("base_url must be a valid URL".to_string());
+            }
+        }
+        let proxy = config
+            .proxy
+            .as_deref()
+            .and_then(|proxy| match Proxy::all(proxy) {
+                Ok(proxy) => Some(proxy),
+                Err(_) => {
+                    problems.push("proxy must be a valid URL".to_string());
+                    None
+                }
+            });
+        if config
+            .poll_backoff
+            .is_some_and(|backoff| !backoff.is_finite() || backoff < 1.0)
+        {
+            problems.push("poll_backoff must be a finite number of at least 1".to_string());
+        }
+
+        if !problems.is_empty() {
+            return Err(DiffusionError::InvalidConfig(problems));
+        }
+
+        let mut poll_config = PollConfig::new(poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL));
+        if let Some(backoff) = config.poll_backoff {
+            poll_config = poll_config.update_backoff(backoff);
+        }
+        if let Some(max_interval) = poll_max_interval {
+            poll_config = poll_config.update_max_interval(max_interval);
+        }
+        if let Some(retries) = config.poll_retries {
+            poll_config = poll_config.update_retries(
+                retries,
+                poll_retry_delay.unwrap_or(DEFAULT_POLL_RETRY_DELAY),
+            );
+        }
+        if poll_initial_delay.is_some() || poll_initial_jitter.is_some() {
+            poll_config = poll_config.update_initial_delay(
+                poll_initial_delay.unwrap_or_default(),
+                poll_initial_jitter.unwrap_or_default(),
+            );
+        }
+
+        let mut builder = Self::builder(config.api_key).poll_config(poll_config);
+        if let Some(base_url) = config.base_url {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = submit_timeout {
+            builder = builder.submit_timeout(timeout);
+        }
+        if let Some(timeout) = status_timeout {
+            builder = builder.status_timeout(timeout);
+        }
+        if let Some(age) = max_token_age {
+            builder = builder.max_token_age(age);
+        }
+        if let Some(max) = config.max_response_bytes {
+            builder = builder.max_response_bytes(max);
+        }
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy);
+        }
+
+        builder.build()
+    }
+
+    /// Start building a client with non-default settings
+    pub fn builder(key: String) -> DiffusionClientBuilder {
+        DiffusionClientBuilder::new(key)
+    }
+
+    /// Create a new client using a different API key. The underlying connection
+    /// pool is shared with this client, making this cheap enough to do per user.
+    pub fn with_different_key(&self, key: String) -> Result<DiffusionClient> {
+        Ok(Self {
+            key: auth_header(&key)?.1,
+            ..self.clone()
+        })
+    }
+
+    /// Create a new client that rejects any prompt containing one of the given terms with
+    /// [`DiffusionError::BlockedContent`] before it is sent. Matching is case-insensitive
+    /// and only whole words count, so blocking "cat" doesn't block "concatenate". This
+    /// replaces any blocklist the client already had.
+    pub fn with_blocklist(&self, terms: Vec<String>) -> DiffusionClient {
+        Self {
+            blocklist: lowercase_terms(terms),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the token is older than the client's configured maximum token age, going
+    /// by the client's [`Clock`], which is what the `check_and_wait` methods enforce
+    pub fn is_token_expired(&self, token: &ImageToken) -> bool {
+        token.is_expired_at(self.clock.now())
+    }
 
-        let bearer = format!("Bearer {}", key);
-        let mut key = header::HeaderValue::from_str(&bearer)?;
-        key.set_sensitive(true);
-        headers.insert(header::AUTHORIZATION, key);
+    /// Set the steps, model, size, orientation, and negative prompt used by
+    /// [`generate_prompt()`](Self::generate_prompt), which only takes a prompt. The prompt of
+    /// the template itself is ignored. Requests passed in full, such as to
+    /// [`generate()`](Self::generate), always use their own settings and never these.
+    /// Without defaults, the same settings as [`ImageRequest::new()`] are used.
+    pub fn set_request_defaults(&mut self, defaults: ImageRequest) {
+        self.request_defaults = Some(defaults);
+    }
+
+    /// The headers for a single request: the caller's extra headers plus authorization
+    /// and the content type
+    fn request_headers(&self, mut headers: HeaderMap) -> HeaderMap {
+        headers.insert(header::AUTHORIZATION, self.key.clone());
+        headers.insert(header::CONTENT_TYPE, self.content_type.clone());
+        headers
+    }
+
+    /// Read the response body and deserialize it as JSON
+    async fn read_json<T: DeserializeOwned>(&self, res: Response) -> Result<T> {
+        Ok(serde_json::from_slice(&self.read_body(res).await?)?)
+    }
 
-        headers.insert(header::ACCEPT, "application/json".try_into()?);
+    /// Read the response body in chunks, aborting the read as soon as it grows past the
+    /// configured maximum size
+    async fn read_body(&self, mut res: Response) -> Result<Vec<u8>> {
+        let content_length = res.content_length();
+        if content_length.is_some_and(|len| len > self.max_response_bytes as u64) {
+            return Err(DiffusionError::ResponseTooLarge);
+        }
 
-        let api = Client::builder().default_headers(headers).build()?;
+        let mut body = Vec::new();
+        loop {
+            let chunk = match res.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                // a dropped connection surfaces as a body error, unlike a timeout
+                Err(e) if e.is_body() && !e.is_timeout() => {
+                    return Err(DiffusionError::IncompleteResponse)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if body.len() + chunk.len() > self.max_response_bytes {
+                return Err(DiffusionError::ResponseTooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        if content_length.is_some_and(|len| (body.len() as u64) < len) {
+            return Err(DiffusionError::IncompleteResponse);
+        }
 
-        Ok(Self { api })
+        Ok(body)
     }
 
     /// Request an image be created, using the given request to fill out the parameters
     /// for the API image to create. It returns a token that can then be used to check
     /// the status of the image and received the image when complete.
     pub async fn request_image(&self, request: ImageRequest) -> Result<ImageToken> {
-        let body = self
+        self.submit(request, HeaderMap::new()).await
+    }
+
+    /// The same as [`request_image()`](DiffusionClient::request_image), but adding the given
+    /// headers to this one request, such as a correlation ID for tracing. The client's
+    /// `Authorization` and `Content-Type` headers always take precedence over ones given here.
+    pub async fn request_image_with_headers(
+        &self,
+        request: ImageRequest,
+        headers: HeaderMap,
+    ) -> Result<ImageToken> {
+        self.submit(request, headers).await
+    }
+
+    async fn submit(&self, request: ImageRequest, headers: HeaderMap) -> Result<ImageToken> {
+        request.validate()?;
+        self.check_blocklist(&request.prompt)?;
+
+        let mut req = self
             .api
-            .post(API_URL)
-            .json(&request)
-            .send()
-            .await?
-            .json::<TokenBody>()
-            .await?;
+            .post(format!("{}{}", self.base_url, IMAGE_PATH))
+            .headers(self.request_headers(headers))
+            .json(&request);
+        if let Some(timeout) = self.submit_timeout {
+            req = req.timeout(timeout);
+        }
+        let res = req.send().await?;
+        let body = self.read_json::<TokenBody>(res).await?;
+
+        let mut token = ImageToken::from(body);
+        let now = self.clock.now();
+        token.issued_at = Some(now);
+        // an age too long to represent never expires, rather than panicking after the
+        // server has already accepted the job
+        token.expires_at = self.max_token_age.and_then(|age| now.checked_add(age));
+
+        if let Some(store) = &self.token_store {
+            // the job is already running and paid for, so the token must not be lost
+            if let Err(e) = store.save(&token, &request) {
+                return Err(DiffusionError::TokenNotSaved {
+                    token,
+                    source: Box::new(e),
+                });
+            }
+        }
 
-        Ok(body.into())
+        Ok(token)
     }
 
     /// Check the status of the image using the token received from
     /// a [`request_image()`](DiffusionClient::request_image) call
     pub async fn check_status(&self, token: ImageToken) -> Result<DiffusionImage> {
+        self.status(token, None, HeaderMap::new()).await
+    }
+
+    /// The same as [`check_status()`](DiffusionClient::check_status), but adding the given
+    /// headers to this one request. The client's `Authorization` and `Content-Type` headers
+    /// always take precedence over ones given here.
+    pub async fn check_status_with_headers(
+        &self,
+        token: ImageToken,
+        headers: HeaderMap,
+    ) -> Result<DiffusionImage> {
+        self.status(token, None, headers).await
+    }
+
+    /// The same as [`check_status()`](DiffusionClient::check_status), but giving up with
+    /// [`DiffusionError::Timeout`] if the call takes longer than the given timeout. The
+    /// timeout only covers this one call and takes precedence over the client's status
+    /// timeout. It is separate from the maximum wait time of
+    /// [`check_and_wait()`](DiffusionClient::check_and_wait), so a poll started just
+    /// before that deadline can still run for the full timeout.
+    pub async fn check_status_timeout(
+        &self,
+        token: ImageToken,
+        timeout: Duration,
+    ) -> Result<DiffusionImage> {
+        self.status(token, Some(timeout), HeaderMap::new())
+            .await
+            .map_err(|e| match e {
+                DiffusionError::ReqwestError(e) if e.is_timeout() => DiffusionError::Timeout,
+                e => e,
+            })
+    }
+
+    /// Check the status of several images at once, returning the statuses in the same order
+    /// as the given tokens. The API has no bulk status endpoint, so this makes a few
+    /// individual checks at a time. If any check fails, the first error is returned.
+    pub async fn check_status_bulk(&self, tokens: &[ImageToken]) -> Result<Vec<ImageStatus>> {
+        stream::iter(tokens)
+            .map(|token| async move {
+                match self.check_status(token.clone()).await {
+                    Ok(image) => Ok(ImageStatus::Complete(image)),
+                    Err(DiffusionError::ImageStatusNotReady) => Ok(ImageStatus::Pending),
+                    Err(e) => Err(e),
+                }
+            })
+            .buffered(BULK_STATUS_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Check whether the image is complete without downloading it. The API has no cheap
+    /// readiness query, so this makes the same status request as
+    /// [`check_status()`](DiffusionClient::check_status), but drops the response without
+    /// reading the image body.
+    pub async fn is_ready(&self, token: ImageToken) -> Result<bool> {
+        let res = self.status_response(token, None, HeaderMap::new()).await?;
+
+        match res.status() {
+            StatusCode::NO_CONTENT => Ok(false),
+            StatusCode::CREATED => Ok(true),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(DiffusionError::TokenNotFound),
+            code => Err(DiffusionError::UnknownHttpError(code)),
+        }
+    }
+
+    async fn status(
+        &self,
+        token: ImageToken,
+        timeout: Option<Duration>,
+        headers: HeaderMap,
+    ) -> Result<DiffusionImage> {
         let res = self
-            .api
-            .post(STATUS_URL)
-            .json(&TokenBody::from(token))
-            .send()
+            .status_response(token.clone(), timeout, headers)
             .await?;
 
         match res.status() {
             StatusCode::NO_CONTENT => Err(DiffusionError::ImageStatusNotReady),
-            StatusCode::CREATED => Ok(res.json::<StatusResponse>().await?.data),
+            StatusCode::CREATED => {
+                let body = self.read_body(res).await?;
+                let mut image = serde_json::from_slice::<StatusResponse>(&body)?.data;
+                if self.capture_raw_responses {
+                    image.raw_response = Some(body);
+                }
+                self.forget_token(&token);
+
+                Ok(image)
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                self.forget_token(&token);
+                Err(DiffusionError::TokenNotFound)
+            }
             code => Err(DiffusionError::UnknownHttpError(code)),
         }
     }
 
+    async fn status_response(
+        &self,
+        token: ImageToken,
+        timeout: Option<Duration>,
+        headers: HeaderMap,
+    ) -> Result<Response> {
+        let mut req = self
+            .api
+            .post(format!("{}{}", self.base_url, STATUS_PATH))
+            .headers(self.request_headers(headers))
+            .json(&TokenBody::from(token));
+        if let Some(timeout) = timeout.or(self.status_timeout) {
+            req = req.timeout(timeout);
+        }
+
+        Ok(req.send().await?)
+    }
+
+    /// Request an image and wait for it to complete, combining
+    /// [`request_image()`](DiffusionClient::request_image) and
+    /// [`check_and_wait()`](DiffusionClient::check_and_wait)
+    pub async fn generate(
+        &self,
+        request: ImageRequest,
+        max_wait_time: Option<Duration>,
+    ) -> Result<DiffusionImage> {
+        let token = self.request_image(request).await?;
+        self.check_and_wait(token, max_wait_time).await
+    }
+
+    /// Generate an image for the prompt using the client's
+    /// [request defaults](Self::set_request_defaults) for everything else
+    pub async fn generate_prompt(
+        &self,
+        prompt: impl Into<String>,
+        max_wait_time: Option<Duration>,
+    ) -> Result<DiffusionImage> {
+        let request = match &self.request_defaults {
+            Some(defaults) => ImageRequest {
+                prompt: prompt.into(),
+                ..defaults.clone()
+            },
+            None => ImageRequest::new(prompt),
+        };
+        self.generate(request, max_wait_time).await
+    }
+
+    /// The same as [`generate()`](DiffusionClient::generate), but also measuring how long
+    /// each phase took on the client, for tracking generation latency
+    pub async fn generate_timed(
+        &self,
+        request: ImageRequest,
+        max_wait_time: Option<Duration>,
+    ) -> Result<(DiffusionImage, Timings)> {
+        let started = self.clock.now();
+        let token = self.request_image(request).await?;
+        let submitted = self.clock.now();
+        let image = self.check_and_wait(token, max_wait_time).await?;
+        let finished = self.clock.now();
+
+        let timings = Timings {
+            submit: submitted.saturating_duration_since(started),
+            queue_wait: finished.saturating_duration_since(submitted),
+            total: finished.saturating_duration_since(started),
+        };
+
+        Ok((image, timings))
+    }
+
+    /// Generate an image and stream the decoded bytes into the given writer as soon as it
+    /// is ready, returning only the metadata. This suits servers proxying images straight
+    /// into a response, as the decoded image is never held in memory all at once.
+    pub async fn generate_to_writer<W: AsyncWrite + Unpin>(
+        &self,
+        request: ImageRequest,
+        max_wait_time: Option<Duration>,
+        writer: W,
+    ) -> Result<DiffusionImageMeta> {
+        let image = self.generate(request, max_wait_time).await?;
+        image.decode_to_writer(writer).await?;
+
+        Ok(image.meta())
+    }
+
     /// Check the status of the image and wait for a maximum amount of time for the image
     /// to complete before returning the image response. This method will continue to poll
-    /// every five seconds until either the image has been completed or the max time is hit.
+    /// on the client's poll schedule (every five seconds by default) until either the image
+    /// has been completed or the max time is hit.
     /// If `None` is passed for maximum time, then the method will poll indefinitely until the
-    /// image is complete.
+    /// image is complete. If the token has expired or the API doesn't recognize it, the
+    /// method stops polling and returns [`DiffusionError::TokenExpired`] or
+    /// [`DiffusionError::TokenNotFound`] respectively.
     pub async fn check_and_wait(
         &self,
         token: ImageToken,
         max_wait_time: Option<Duration>,
     ) -> Result<DiffusionImage> {
-        let time_threshold = max_wait_time.map(|d| Instant::now() + d);
-        loop {
-            match self.check_status(token.clone()).await {
-                Ok(image) => return Ok(image),
-                // utxo-suggested poll duration is five seconds
-                _ => match time_threshold {
-                    Some(t) if Instant::now() >= t => return Err(DiffusionError::TimeExpired),
-                    _ => Delay::new(Duration::from_secs(5)).await,
-                },
-            }
-        }
+        self.check_and_wait_with(token, max_wait_time, self.poll_config.clone())
+            .await
     }
-}
-
-/// An image request to notify the API of the parameters of
-/// the image to create
-#[derive(Debug, Serialize)]
-pub struct ImageRequest {
-    prompt: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    negative: Option<String>,
-    steps: ImageSteps,
-    model: ImageModel,
-    size: ImageSize,
-    orientation: ImageOrientation,
-}
 
-impl ImageRequest {
-    pub fn new(prompt: String) -> Self {
-        Self {
-            prompt,
-            negative: None,
-            steps: ImageSteps::Fifty,
-            model: ImageModel::BeautyRealism,
-            size: ImageSize::Small,
-            orientation: ImageOrientation::Landscape,
-        }
+    /// The same as [`check_and_wait()`](DiffusionClient::check_and_wait), but polling on the
+    /// schedule described by the given [`PollConfig`] instead of the client's.
+    pub async fn check_and_wait_with(
+        &self,
+        token: ImageToken,
+        max_wait_time: Option<Duration>,
+        config: PollConfig,
+    ) -> Result<DiffusionImage> {
+        self.wait(token, max_wait_time, config, None, &|_| true)
+            .await
     }
 
-    pub fn update_negative_prompt(mut self, prompt: String) -> Self {
-        self.negative = Some(prompt);
-        self
+    /// The same as [`check_and_wait()`](DiffusionClient::check_and_wait), but only returning
+    /// an image that satisfies the predicate. Completed images that don't match are
+    /// skipped and polling continues on the client's schedule, so if no matching image is
+    /// ever reported this ends with [`DiffusionError::TimeExpired`] once the maximum wait
+    /// time passes, or keeps polling forever without one.
+    pub async fn check_and_wait_for(
+        &self,
+        token: ImageToken,
+        max_wait_time: Option<Duration>,
+        predicate: impl Fn(&DiffusionImage) -> bool + Send + Sync,
+    ) -> Result<DiffusionImage> {
+        self.wait(
+            token,
+            max_wait_time,
+            self.poll_config.clone(),
+            None,
+            &predicate,
+        )
+        .await
     }
 
-    pub fn update_steps(mut self, steps: ImageSteps) -> Self {
-        self.steps = steps;
-        self
-    }
+    /// Check the status of the image and wait for it to complete, as configured by the given
+    /// [`WaitOptions`]. This is the most flexible way to wait on an image, supporting a
+    /// maximum wait time, a custom poll schedule, cancellation, and progress updates at
+    /// once. Without a poll schedule in the options, the client's is used.
+    pub async fn check_and_wait_full(
+        &self,
+        token: ImageToken,
+        options: WaitOptions,
+    ) -> Result<DiffusionImage> {
+        let wait = self.wait(
+            token.clone(),
+            options.max_wait,
+            options
+                .poll_config
+                .unwrap_or_else(|| self.poll_config.clone()),
+            options.progress,
+            &|_| true,
+        );
 
-    pub fn update_model(mut self, model: ImageModel) -> Self {
-        self.model = model;
-        self
+        match options.cancel {
+            Some(cancel) => Abortable::new(wait, cancel)
+                .await
+                .map_err(|_| DiffusionError::Cancelled(token))?,
+            None => wait.await,
+        }
     }
 
-    pub fn update_size(mut self, size: ImageSize) -> Self {
-        self.size = size;
-        self
+    /// Check the status of the image and wait for it to complete, stopping early with
+    /// [`DiffusionError::Cancelled`] once the matching
+    /// [`AbortHandle`](futures::future::AbortHandle) is aborted. Cancelling only stops the
+    /// local poll loop; the image keeps generating on the server, and the token handed back
+    /// in the error can be passed to [`check_status()`](Self::check_status) or any of the
+    /// `check_and_wait` methods to resume.
+    pub async fn check_and_wait_cancellable(
+        &self,
+        token: ImageToken,
+        max_wait_time: Option<Duration>,
+        cancel: AbortRegistration,
+    ) -> Result<DiffusionImage> {
+        let options = WaitOptions {
+            max_wait: max_wait_time,
+            poll_config: None,
+            cancel: Some(cancel),
+            progress: None,
+        };
+        self.check_and_wait_full(token, options).await
     }
 
-    pub fn update_orientation(mut self, orientation: ImageOrientation) -> Self {
-        self.orientation = orientation;
-        self
+    /// Remove a token that will never be polled again from the token store. A failed
+    /// removal is ignored, since the outcome for the token is already decided.
+    fn forget_token(&self, token: &ImageToken) {
+        if let Some(store) = &self.token_store {
+            let _ = store.remove(token);
+        }
     }
-}
 
-/// The available steps provided through the API
-#[derive(Debug, Serialize_repr, Deserialize_repr, Clone)]
-#[repr(u16)]
-pub enum ImageSteps {
-    Fifty = 50,
-    OneHundred = 100,
-    OneHundredFifty = 150,
-    TwoHundred = 200,
-}
+    async fn wait(
+        &self,
+        token: ImageToken,
+        max_wait_time: Option<Duration>,
+        config: PollConfig,
+        mut progress: Option<mpsc::Sender<PollProgress>>,
+        accept: &(dyn Fn(&DiffusionImage) -> bool + Send + Sync),
+    ) -> Result<DiffusionImage> {
+        let started = self.clock.now();
+        let time_threshold = max_wait_time.and_then(|d| started.checked_add(d));
+        let mut interval = config.interval;
+        let mut polls = 0;
 
-impl Display for ImageSteps {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Fifty => write!(f, "50"),
-            Self::OneHundred => write!(f, "100"),
-            Self::OneHundredFifty => write!(f, "150"),
-            Self::TwoHundred => write!(f, "200"),
+        // the initial delay must not run past the maximum wait time either
+        let mut initial_delay = config.jittered_initial_delay();
+        if let Some(t) = time_threshold {
+            initial_delay = initial_delay.min(t.saturating_duration_since(started));
+        }
+        if !initial_delay.is_zero() {
+            self.clock.sleep(initial_delay).await;
         }
-    }
-}
 
-impl TryFrom<u16> for ImageSteps {
-    type Error = DiffusionError;
+        loop {
+            if token.is_expired_at(self.clock.now()) {
+                self.forget_token(&token);
+                return Err(DiffusionError::TokenExpired);
+            }
 
-    fn try_from(value: u16) -> std::result::Result<Self, Self::Error> {
-        match value {
-            50 => Ok(Self::Fifty),
-            100 => Ok(Self::OneHundred),
-            150 => Ok(Self::OneHundredFifty),
-            200 => Ok(Self::TwoHundred),
-            _ => Err(DiffusionError::InvalidStepAmount),
+            let result = self.poll(&token, &config, time_threshold).await;
+            polls += 1;
+            if let Some(progress) = &mut progress {
+                // progress is best effort, so a full or closed channel is ignored
+                let _ = progress.try_send(PollProgress {
+                    polls,
+                    elapsed: self.clock.now().saturating_duration_since(started),
+                });
+            }
+
+            match result {
+                Ok(image) if accept(&image) => {
+                    self.record_wait(&token, &image);
+                    return Ok(image);
+                }
+                Err(DiffusionError::TokenNotFound) => return Err(DiffusionError::TokenNotFound),
+                _ => match time_threshold {
+                    Some(t) if self.clock.now() >= t => return Err(DiffusionError::TimeExpired),
+                    _ => {
+                        self.clock.sleep(interval).await;
+                        interval = config.next_interval(interval);
+                    }
+                },
+            }
         }
     }
-}
 
-#[cfg(feature = "clap")]
-impl clap::ValueEnum for ImageSteps {
-    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
-        use clap::builder::PossibleValue;
+    /// Reject the prompt if it contains a blocked term as a whole word
+    fn check_blocklist(&self, prompt: &str) -> Result<()> {
+        let prompt = prompt.to_lowercase();
+        let is_boundary = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
 
-        match self {
-            Self::Fifty => Some(PossibleValue::new("50")),
-            Self::OneHundred => Some(PossibleValue::new("100")),
-            Self::OneHundredFifty => Some(PossibleValue::new("150")),
-            Self::TwoHundred => Some(PossibleValue::new("200")),
+        for term in self.blocklist.iter() {
+            let blocked = prompt.match_indices(term.as_str()).any(|(start, _)| {
+                is_boundary(prompt[..start].chars().next_back())
+                    && is_boundary(prompt[start + term.len()..].chars().next())
+            });
+            if blocked {
+                return Err(DiffusionError::BlockedContent { term: term.clone() });
+            }
         }
-    }
 
-    fn value_variants<'a>() -> &'a [Self] {
-        &[
-            Self::Fifty,
-            Self::OneHundred,
-            Self::OneHundredFifty,
-            Self::TwoHundred,
-        ]
+        Ok(())
     }
-}
 
-/// The available image models provided through the API
-#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "snake_case")]
-pub enum ImageModel {
-    BeautyRealism,
-    AestheticRealism,
-    AnimeRealism,
-    AnalogRealism,
-    DreamReality,
-    StableDiffusion,
-    ToonAnimated,
-    FantasyAnimated,
-}
+    /// Check the status once, retrying transient failures as configured, until the deadline,
+    /// before giving the error back to the poll loop
+    async fn poll(
+        &self,
+        token: &ImageToken,
+        config: &PollConfig,
+        deadline: Option<Instant>,
+    ) -> Result<DiffusionImage> {
+        let mut retries = config.retries;
+        let mut incomplete_retries = INCOMPLETE_RESPONSE_RETRIES;
+        let mut delay = config.retry_delay;
+        loop {
+            let e = match self.check_status(token.clone()).await {
+                Err(e) if e.is_transient() => e,
+                res => return res,
+            };
 
-impl Display for ImageModel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::BeautyRealism => write!(f, "beauty_realism"),
-            Self::AestheticRealism => write!(f, "aesthetic_realism"),
-            Self::AnimeRealism => write!(f, "anime_realism"),
-            Self::AnalogRealism => write!(f, "analog_realism"),
-            Self::DreamReality => write!(f, "dream_reality"),
-            Self::StableDiffusion => write!(f, "stable_diffusion"),
-            Self::ToonAnimated => write!(f, "toon_animated"),
-            Self::FantasyAnimated => write!(f, "fantasy_animated"),
+            if retries > 0 {
+                retries -= 1;
+            } else if matches!(e, DiffusionError::IncompleteResponse) && incomplete_retries > 0 {
+                // a cut-off body is retried even without configured retries, rather than
+                // waiting out a whole interval to download it again
+                incomplete_retries -= 1;
+                delay = delay.max(DEFAULT_POLL_RETRY_DELAY);
+            } else {
+                return Err(e);
+            }
+
+            // retrying must not run past the maximum wait time
+            if deadline.is_some_and(|t| self.clock.now() + delay >= t) {
+                return Err(e);
+            }
+            self.clock.sleep(delay).await;
+            delay *= 2;
         }
     }
-}
 
-impl TryFrom<String> for ImageModel {
-    type Error = DiffusionError;
+    /// An estimate of how long an image for the given request will take to complete, based
+    /// on the average of recent completions with the same model, steps, and size seen by
+    /// this client. Returns `None` if wait times aren't being tracked or no matching image
+    /// has completed yet.
+    pub fn estimated_wait(&self, request: &ImageRequest) -> Option<Duration> {
+        let history = self.wait_history.as_ref()?.lock().ok()?;
+        let waits = history.get(&(
+            request.model.clone(),
+            request.steps.clone(),
+            request.size.clone(),
+        ))?;
 
-    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
-        match value.as_str() {
-            "beauty_realism" => Ok(Self::BeautyRealism),
-            "aesthetic_realism" => Ok(Self::AestheticRealism),
-            "anime_realism" => Ok(Self::AnimeRealism),
-            "analog_realism" => Ok(Self::AnalogRealism),
-            "dream_reality" => Ok(Self::DreamReality),
-            "stable_diffusion" => Ok(Self::StableDiffusion),
-            "toon_animated" => Ok(Self::ToonAnimated),
-            "fantasy_animated" => Ok(Self::FantasyAnimated),
-            _ => Err(DiffusionError::InvalidModel),
-        }
+        let total: Duration = waits.iter().sum();
+        Some(total / waits.len() as u32)
     }
-}
 
-/// The available image sizes provided through the API
-#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "snake_case")]
-pub enum ImageSize {
-    Small,
-    Medium,
-    Large,
+    /// Forget all wait times tracked so far
+    pub fn clear_wait_history(&self) {
+        if let Some(mut history) = self.wait_history.as_ref().and_then(|h| h.lock().ok()) {
+            history.clear();
+        }
+    }
+
+    fn record_wait(&self, token: &ImageToken, image: &DiffusionImage) {
+        let (Some(issued_at), Some(history)) = (token.issued_at, &self.wait_history) else {
+            return;
+        };
+        let Ok(mut history) = history.lock() else {
+            return;
+        };
+
+        let waits = history
+            .entry((image.model.clone(), image.steps.clone(), image.size.clone()))
+            .or_default();
+        if waits.len() == WAIT_HISTORY_LEN {
+            waits.pop_front();
+        }
+        waits.push_back(self.clock.now().saturating_duration_since(issued_at));
+    }
+
+    /// Wait on several tokens at once, returning the index and image of whichever completes
+    /// first. All tokens are polled concurrently and share the same maximum wait time. Once
+    /// one completes, polling for the rest stops, though their generations keep running
+    /// server-side. If none complete in time, [`DiffusionError::TimeExpired`] is returned.
+    pub async fn check_and_wait_any(
+        &self,
+        tokens: Vec<ImageToken>,
+        max_wait_time: Option<Duration>,
+    ) -> Result<(usize, DiffusionImage)> {
+        if tokens.is_empty() {
+            return Err(DiffusionError::NoTokens);
+        }
+
+        let waits = tokens.into_iter().enumerate().map(|(i, token)| {
+            Box::pin(async move {
+                let image = self.check_and_wait(token, max_wait_time).await?;
+                Ok::<_, DiffusionError>((i, image))
+            })
+        });
+        let (first, _) = select_ok(waits).await?;
+
+        Ok(first)
+    }
+
+    /// Wait on several tokens at once, returning every result in the same order as the
+    /// given tokens. At most `concurrency` tokens are polled at a time, and all of them
+    /// share one overall deadline. A token that fails or runs out of time doesn't stop
+    /// the others; its slot holds the error instead.
+    pub async fn check_and_wait_all(
+        &self,
+        tokens: Vec<ImageToken>,
+        max_wait_time: Option<Duration>,
+        concurrency: usize,
+    ) -> Vec<Result<DiffusionImage>> {
+        let time_threshold = max_wait_time.and_then(|d| self.clock.now().checked_add(d));
+
+        stream::iter(tokens)
+            .map(|token| {
+                let remaining =
+                    time_threshold.map(|t| t.saturating_duration_since(self.clock.now()));
+                self.check_and_wait(token, remaining)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Generate several images, running at most `concurrency` generations at a time. Each
+    /// image gets the full maximum wait time once it is submitted. The results are in the
+    /// same order as the requests, and one failing doesn't stop the others.
+    pub async fn generate_all(
+        &self,
+        requests: Vec<ImageRequest>,
+        max_wait_time: Option<Duration>,
+        concurrency: usize,
+    ) -> Vec<Result<DiffusionImage>> {
+        stream::iter(requests)
+            .map(|request| self.generate(request, max_wait_time))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Generate an image for each prompt in the text, such as the contents of a prompt file,
+    /// one prompt per line, using the base request for every other setting. Blank lines and
+    /// lines starting with `#` are skipped. At most `concurrency` images are generated at a
+    /// time, and each prompt is returned with its result in order, so partial failures are
+    /// visible.
+    ///
+    /// This takes the file's contents rather than a path on purpose: the library doesn't
+    /// depend on an async runtime, so reading the file is left to the caller's own IO. Saving
+    /// the images is left to the caller too; the CLI's `--prompt-file` option does both.
+    pub async fn generate_from_lines(
+        &self,
+        text: &str,
+        base_request: ImageRequest,
+        max_wait_time: Option<Duration>,
+        concurrency: usize,
+    ) -> Vec<(String, Result<DiffusionImage>)> {
+        let prompts = prompt_lines(text);
+        let requests = prompts
+            .iter()
+            .map(|prompt| ImageRequest {
+                prompt: prompt.clone(),
+                ..base_request.clone()
+            })
+            .collect();
+
+        let results = self
+            .generate_all(requests, max_wait_time, concurrency)
+            .await;
+        prompts.into_iter().zip(results).collect()
+    }
+
+    /// Generate the same prompt with each of the given models, at most `concurrency` at a
+    /// time, returning each image alongside the credits it cost. This makes it easy to
+    /// compare the quality each model gives per credit spent.
+    pub async fn benchmark_models(
+        &self,
+        prompt: impl Into<String>,
+        models: Vec<ImageModel>,
+        steps: ImageSteps,
+        size: ImageSize,
+        max_wait_time: Option<Duration>,
+        concurrency: usize,
+    ) -> Vec<Result<(ImageModel, DiffusionImage, f64)>> {
+        let base = ImageRequest::new(prompt)
+            .update_steps(steps)
+            .update_size(size);
+        let requests = models
+            .iter()
+            .map(|model| base.clone().update_model(model.clone()))
+            .collect();
+
+        self.generate_all(requests, max_wait_time, concurrency)
+            .await
+            .into_iter()
+            .zip(models)
+            .map(|(result, model)| {
+                result.map(|image| {
+                    let credits = image.credits_used;
+                    (model, image, credits)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builder for a [`DiffusionClient`] with non-default settings
+pub struct DiffusionClientBuilder {
+    key: String,
+    max_response_bytes: usize,
+    max_token_age: Option<Duration>,
+    proxies: Vec<Proxy>,
+    track_wait_times: bool,
+    timeout: Option<Duration>,
+    submit_timeout: Option<Duration>,
+    status_timeout: Option<Duration>,
+    token_store: Option<Arc<dyn TokenStore>>,
+    content_type: String,
+    clock: Arc<dyn Clock>,
+    capture_raw_responses: bool,
+    base_url: String,
+    poll_config: PollConfig,
+    blocklist: Vec<String>,
+    request_defaults: Option<ImageRequest>,
+    #[cfg(feature = "cookies")]
+    cookie_store: bool,
+}
+
+impl DiffusionClientBuilder {
+    pub fn new(key: String) -> Self {
+        Self {
+            key,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            max_token_age: None,
+            proxies: Vec::new(),
+            track_wait_times: false,
+            timeout: None,
+            submit_timeout: None,
+            status_timeout: None,
+            token_store: None,
+            content_type: "application/json".to_string(),
+            clock: Arc::new(SystemClock),
+            capture_raw_responses: false,
+            base_url: BASE_URL.to_string(),
+            poll_config: PollConfig::default(),
+            blocklist: Vec::new(),
+            request_defaults: None,
+            #[cfg(feature = "cookies")]
+            cookie_store: false,
+        }
+    }
+
+    /// The maximum size of a response body the client will read before giving up
+    /// with [`DiffusionError::ResponseTooLarge`]. Defaults to 64MB.
+    pub fn max_response_bytes(mut self, max: usize) -> Self {
+        self.max_response_bytes = max;
+        self
+    }
+
+    /// How long a token returned from [`request_image()`](DiffusionClient::request_image)
+    /// stays valid. The API doesn't communicate token expiry, so this is tracked entirely
+    /// client-side. By default tokens never expire, as do tokens with an age too long to
+    /// represent.
+    pub fn max_token_age(mut self, age: Duration) -> Self {
+        self.max_token_age = Some(age);
+        self
+    }
+
+    /// Send requests through the given proxy. Can be called more than once to add
+    /// several proxies.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Keep a short history of how long images take to complete so the client can give
+    /// an [`estimated_wait()`](DiffusionClient::estimated_wait). Off by default.
+    pub fn track_wait_times(mut self, track: bool) -> Self {
+        self.track_wait_times = track;
+        self
+    }
+
+    /// The timeout applied to every call to the API. By default there is no timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout for submitting an image request, overriding the client-wide
+    /// [`timeout()`](DiffusionClientBuilder::timeout) for those calls. By default
+    /// the client-wide timeout is used.
+    pub fn submit_timeout(mut self, timeout: Duration) -> Self {
+        self.submit_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout for each status check, overriding the client-wide
+    /// [`timeout()`](DiffusionClientBuilder::timeout) for those calls. By default
+    /// the client-wide timeout is used.
+    pub fn status_timeout(mut self, timeout: Duration) -> Self {
+        self.status_timeout = Some(timeout);
+        self
+    }
+
+    /// Persist the tokens of pending images in the given store. Tokens are saved when an
+    /// image is requested and removed once the image is received, or once the token is
+    /// found to be unknown to the API or expired. If saving fails, the
+    /// request fails with [`DiffusionError::TokenNotSaved`], which still carries the token.
+    pub fn token_store(mut self, store: impl TokenStore + 'static) -> Self {
+        self.token_store = Some(Arc::new(store));
+        self
+    }
+
+    /// The `Content-Type` header sent with every request body, for gateways that expect
+    /// a specific value. Bodies are always JSON, so this defaults to `application/json`.
+    pub fn content_type(mut self, content_type: String) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// The clock used to time polling. Swapping in a custom [`Clock`] makes the timing of
+    /// [`check_and_wait()`](DiffusionClient::check_and_wait) deterministic, such as in tests
+    /// that advance time instantly. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Keep the exact body of each completed status response on the returned
+    /// [`DiffusionImage`] as [`raw_response`](DiffusionImage::raw_response), so it can be
+    /// archived for auditing. This roughly doubles the memory held per image, since the
+    /// body also contains the base64 image data. Off by default.
+    pub fn capture_raw_responses(mut self, capture: bool) -> Self {
+        self.capture_raw_responses = capture;
+        self
+    }
+
+    /// The host the API is served from. Defaults to `https://diffusion.to`.
+    pub fn base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// The schedule [`check_and_wait()`](DiffusionClient::check_and_wait) polls on.
+    /// Defaults to [`PollConfig::default()`].
+    pub fn poll_config(mut self, poll_config: PollConfig) -> Self {
+        self.poll_config = poll_config;
+        self
+    }
+
+    /// Terms that may not appear in a prompt, matched as whole words regardless of case.
+    /// See [`DiffusionClient::with_blocklist()`]. Defaults to none.
+    pub fn blocklist(mut self, terms: Vec<String>) -> Self {
+        self.blocklist = terms;
+        self
+    }
+
+    /// The settings used for requests made from just a prompt. See
+    /// [`DiffusionClient::set_request_defaults()`].
+    pub fn request_defaults(mut self, defaults: ImageRequest) -> Self {
+        self.request_defaults = Some(defaults);
+        self
+    }
+
+    /// Keep cookies set by the API, or a gateway in front of it, and send them back on
+    /// later requests, so session state such as sticky routing survives from submission
+    /// through polling. Off by default.
+    #[cfg(feature = "cookies")]
+    pub fn cookie_store(mut self, enable: bool) -> Self {
+        self.cookie_store = enable;
+        self
+    }
+
+    pub fn build(self) -> Result<DiffusionClient> {
+        let mut headers = HeaderMap::new();
+        let (name, value) = accept_header();
+        headers.insert(name, value);
+
+        let mut api = Client::builder().default_headers(headers);
+        for proxy in self.proxies {
+            api = api.proxy(proxy);
+        }
+        if let Some(timeout) = self.timeout {
+            api = api.timeout(timeout);
+        }
+        #[cfg(feature = "cookies")]
+        {
+            api = api.cookie_store(self.cookie_store);
+        }
+        let api = api.build()?;
+
+        Ok(DiffusionClient {
+            api,
+            key: auth_header(&self.key)?.1,
+            max_response_bytes: self.max_response_bytes,
+            max_token_age: self.max_token_age,
+            wait_history: self.track_wait_times.then(Default::default),
+            submit_timeout: self.submit_timeout,
+            status_timeout: self.status_timeout,
+            token_store: self.token_store,
+            content_type: header::HeaderValue::from_str(&self.content_type)?,
+            clock: self.clock,
+            capture_raw_responses: self.capture_raw_responses,
+            base_url: self.base_url,
+            poll_config: self.poll_config,
+            blocklist: lowercase_terms(self.blocklist),
+            request_defaults: self.request_defaults,
+        })
+    }
+}
+
+/// The `Authorization` header the API expects for the given key, for sending requests
+/// without a [`DiffusionClient`]. The value is marked sensitive so it is left out of
+/// debug output.
+pub fn auth_header(api_key: &str) -> Result<(header::HeaderName, header::HeaderValue)> {
+    let mut value = header::HeaderValue::from_str(&format!("Bearer {}", api_key))?;
+    value.set_sensitive(true);
+
+    Ok((header::AUTHORIZATION, value))
 }
 
-impl Display for ImageSize {
+/// The `Accept` header the API expects, for sending requests without a
+/// [`DiffusionClient`]
+pub fn accept_header() -> (header::HeaderName, header::HeaderValue) {
+    (
+        header::ACCEPT,
+        header::HeaderValue::from_static("application/json"),
+    )
+}
+
+/// Normalize blocklist terms for case-insensitive matching, dropping empty ones
+fn lowercase_terms(terms: Vec<String>) -> Arc<[String]> {
+    terms
+        .into_iter()
+        .map(|term| term.trim().to_lowercase())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/// The source of time used while polling for images
+pub trait Clock: Send + Sync {
+    /// The current instant
+    fn now(&self) -> Instant;
+
+    /// Wait for the given amount of time to pass
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The default [`Clock`], using the system clock and real timers
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+/// Storage for the tokens of images that are still being generated, so polling can be
+/// resumed after a crash or restart by loading the tokens with
+/// [`load_all()`](TokenStore::load_all). Implement this to back the store with a file or
+/// database, or use [`MemoryTokenStore`].
+pub trait TokenStore: Send + Sync {
+    /// Save a newly issued token along with the request that created it
+    fn save(&self, token: &ImageToken, request: &ImageRequest) -> Result<()>;
+
+    /// Load every token that has been saved but not yet removed
+    fn load_all(&self) -> Result<Vec<ImageToken>>;
+
+    /// Remove a token whose image has been received
+    fn remove(&self, token: &ImageToken) -> Result<()>;
+}
+
+impl<T: TokenStore + ?Sized> TokenStore for Arc<T> {
+    fn save(&self, token: &ImageToken, request: &ImageRequest) -> Result<()> {
+        (**self).save(token, request)
+    }
+
+    fn load_all(&self) -> Result<Vec<ImageToken>> {
+        (**self).load_all()
+    }
+
+    fn remove(&self, token: &ImageToken) -> Result<()> {
+        (**self).remove(token)
+    }
+}
+
+/// A [`TokenStore`] that keeps tokens in memory
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<HashMap<String, ImageRequest>>,
+}
+
+impl MemoryTokenStore {
+    fn tokens(&self) -> MutexGuard<'_, HashMap<String, ImageRequest>> {
+        self.tokens.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn save(&self, token: &ImageToken, request: &ImageRequest) -> Result<()> {
+        self.tokens()
+            .insert(token.as_str().to_string(), request.clone());
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<ImageToken>> {
+        Ok(self.tokens().keys().map(|t| t.as_str().into()).collect())
+    }
+
+    fn remove(&self, token: &ImageToken) -> Result<()> {
+        self.tokens().remove(token.as_str());
+        Ok(())
+    }
+}
+
+/// The schedule used to poll the status of an image while waiting for it to complete.
+/// After each poll, the interval is multiplied by the backoff factor, up to the maximum
+/// interval. A poll that fails with a transient error (a connection problem, a timeout, a
+/// response cut off partway, or a 5xx from the API) can be retried a few times, doubling a
+/// short delay between each attempt, before it counts as a failed poll. A response cut off
+/// partway is always retried up to twice more, starting at least a second apart.
+#[derive(Debug, Clone)]
+pub struct PollConfig {
+    interval: Duration,
+    backoff: f64,
+    max_interval: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    initial_delay: Duration,
+    initial_jitter: Duration,
+}
+
+impl PollConfig {
+    /// Poll at a fixed interval with no backoff or retries
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            backoff: 1.0,
+            max_interval: interval,
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            initial_delay: Duration::ZERO,
+            initial_jitter: Duration::ZERO,
+        }
+    }
+
+    /// For quick jobs like small images with few steps. Polls every second with no backoff.
+    pub fn fast() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+
+    /// For big jobs like large images with many steps. Polls after 10 seconds, backing off
+    /// by 1.5x each poll up to once a minute, and retries transient failures up to three
+    /// times starting one second apart.
+    pub fn patient() -> Self {
+        Self::new(Duration::from_secs(10))
+            .update_backoff(1.5)
+            .update_max_interval(Duration::from_secs(60))
+            .update_retries(3, Duration::from_secs(1))
+    }
+
+    /// The factor the interval is multiplied by after each poll. Values below one are
+    /// treated as one.
+    pub fn update_backoff(mut self, backoff: f64) -> Self {
+        self.backoff = backoff.max(1.0);
+        self
+    }
+
+    /// The longest the interval is allowed to grow to through backoff
+    pub fn update_max_interval(mut self, max_interval: Duration) -> Self {
+        self.max_interval = max_interval.max(self.interval);
+        self
+    }
+
+    /// How many times a poll failing with a transient error is retried, and the delay
+    /// before the first retry. The delay doubles after each retry. Retries stop early
+    /// rather than sleeping past the maximum wait time.
+    pub fn update_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// How long to wait before the first poll, for images that are known to never finish
+    /// right away. A random extra delay of up to `jitter` is added so many clients starting
+    /// at once don't all poll together. Both default to zero, polling immediately.
+    pub fn update_initial_delay(mut self, delay: Duration, jitter: Duration) -> Self {
+        self.initial_delay = delay;
+        self.initial_jitter = jitter;
+        self
+    }
+
+    /// The interval after backing off from the given one, capped at the maximum. A product
+    /// too large for a `Duration` is capped the same way instead of overflowing.
+    fn next_interval(&self, interval: Duration) -> Duration {
+        Duration::try_from_secs_f64(interval.as_secs_f64() * self.backoff)
+            .map_or(self.max_interval, |next| next.min(self.max_interval))
+    }
+
+    /// The initial delay with a fresh amount of jitter added
+    fn jittered_initial_delay(&self) -> Duration {
+        // RandomState is randomly seeded, which is plenty for spreading out polls
+        let random = RandomState::new().hash_one(()) as f64 / u64::MAX as f64;
+        self.initial_delay
+            .saturating_add(self.initial_jitter.mul_f64(random))
+    }
+}
+
+impl Default for PollConfig {
+    /// Polls every five seconds with no backoff
+    fn default() -> Self {
+        Self::new(DEFAULT_POLL_INTERVAL)
+    }
+}
+
+/// The settings for a [`DiffusionClient`] in a form that can be loaded from a
+/// configuration file and passed to [`DiffusionClient::from_config()`]. Durations are
+/// given in seconds, and every setting except the API key is optional. The poll interval
+/// must be greater than zero, and retries without a delay start one second apart.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub api_key: String,
+    pub base_url: Option<String>,
+    pub proxy: Option<String>,
+    pub timeout_secs: Option<f64>,
+    pub submit_timeout_secs: Option<f64>,
+    pub status_timeout_secs: Option<f64>,
+    pub max_response_bytes: Option<usize>,
+    pub max_token_age_secs: Option<f64>,
+    pub poll_interval_secs: Option<f64>,
+    pub poll_backoff: Option<f64>,
+    pub poll_max_interval_secs: Option<f64>,
+    pub poll_retries: Option<u32>,
+    pub poll_retry_delay_secs: Option<f64>,
+    pub poll_initial_delay_secs: Option<f64>,
+    pub poll_initial_jitter_secs: Option<f64>,
+}
+
+impl std::fmt::Debug for Config {
+    /// Redacts the API key, like the client does with its authorization header
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Small => write!(f, "small"),
-            Self::Medium => write!(f, "medium"),
-            Self::Large => write!(f, "large"),
+        f.debug_struct("Config")
+            .field("api_key", &"<redacted>")
+            .field("base_url", &self.base_url)
+            .field("proxy", &self.proxy)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("submit_timeout_secs", &self.submit_timeout_secs)
+            .field("status_timeout_secs", &self.status_timeout_secs)
+            .field("max_response_bytes", &self.max_response_bytes)
+            .field("max_token_age_secs", &self.max_token_age_secs)
+            .field("poll_interval_secs", &self.poll_interval_secs)
+            .field("poll_backoff", &self.poll_backoff)
+            .field("poll_max_interval_secs", &self.poll_max_interval_secs)
+            .field("poll_retries", &self.poll_retries)
+            .field("poll_retry_delay_secs", &self.poll_retry_delay_secs)
+            .field("poll_initial_delay_secs", &self.poll_initial_delay_secs)
+            .field("poll_initial_jitter_secs", &self.poll_initial_jitter_secs)
+            .finish()
+    }
+}
+
+/// Everything that controls how [`check_and_wait_full()`](DiffusionClient::check_and_wait_full)
+/// waits on an image
+#[derive(Debug, Default)]
+pub struct WaitOptions {
+    max_wait: Option<Duration>,
+    poll_config: Option<PollConfig>,
+    cancel: Option<AbortRegistration>,
+    progress: Option<mpsc::Sender<PollProgress>>,
+}
+
+impl WaitOptions {
+    /// Wait indefinitely on the client's poll schedule, without cancellation or progress
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum time to wait before giving up with [`DiffusionError::TimeExpired`].
+    /// Defaults to waiting indefinitely.
+    pub fn update_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// The schedule to poll on. Defaults to the client's, set with
+    /// [`DiffusionClientBuilder::poll_config()`].
+    pub fn update_poll_config(mut self, poll_config: PollConfig) -> Self {
+        self.poll_config = Some(poll_config);
+        self
+    }
+
+    /// Stop waiting with [`DiffusionError::Cancelled`] once the matching
+    /// [`AbortHandle`](futures::future::AbortHandle) is aborted. The image is not cancelled
+    /// on the server. Defaults to no cancellation.
+    pub fn update_cancel(mut self, cancel: AbortRegistration) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Send a [`PollProgress`] to the channel after every poll. Updates are dropped rather
+    /// than slowing down polling if the channel is full. Defaults to no progress updates.
+    pub fn update_progress(mut self, progress: mpsc::Sender<PollProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+/// How long each phase of generating an image took, measured on the client
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    /// Submitting the request and receiving a token
+    pub submit: Duration,
+    /// Waiting for the image to complete after the token was received
+    pub queue_wait: Duration,
+    /// The whole generation, from submitting to receiving the image
+    pub total: Duration,
+}
+
+/// Progress of waiting on an image, sent after every poll
+#[derive(Debug, Clone)]
+pub struct PollProgress {
+    /// How many times the status has been checked so far
+    pub polls: u32,
+    /// How long has passed since waiting started
+    pub elapsed: Duration,
+}
+
+/// An image request to notify the API of the parameters of
+/// the image to create
+#[derive(Debug, Serialize, Clone)]
+pub struct ImageRequest {
+    prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative: Option<String>,
+    steps: ImageSteps,
+    model: ImageModel,
+    size: ImageSize,
+    orientation: ImageOrientation,
+}
+
+impl ImageRequest {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            negative: None,
+            steps: ImageSteps::Fifty,
+            model: ImageModel::BeautyRealism,
+            size: ImageSize::Small,
+            orientation: ImageOrientation::Landscape,
         }
     }
+
+    /// Set the negative prompt. An empty string is sent as `"negative": ""` rather than
+    /// being left out, for backends that treat the two differently; use
+    /// [`clear_negative_prompt()`](Self::clear_negative_prompt) to leave it out.
+    pub fn update_negative_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.negative = Some(prompt.into());
+        self
+    }
+
+    /// Remove any negative prompt so it is left out of the request entirely
+    pub fn clear_negative_prompt(mut self) -> Self {
+        self.negative = None;
+        self
+    }
+
+    pub fn update_steps(mut self, steps: ImageSteps) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    pub fn update_model(mut self, model: ImageModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn update_size(mut self, size: ImageSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn update_orientation(mut self, orientation: ImageOrientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    /// Run every client-side check on the request without sending it, returning
+    /// the first problem found. [`request_image()`](DiffusionClient::request_image)
+    /// calls this before submitting, so invalid requests fail without a round trip.
+    pub fn validate(&self) -> Result<()> {
+        if self.prompt.trim().is_empty() {
+            return Err(DiffusionError::EmptyPrompt);
+        }
+
+        Ok(())
+    }
+
+    /// Render the request as a runnable `curl` command against the API hosted at
+    /// `base_url` (e.g. `https://diffusion.to`). The API key is never included; the
+    /// command reads it from the `DIFFUSION_API_KEY` environment variable instead.
+    pub fn to_curl(&self, base_url: &str) -> String {
+        let body = serde_json::to_string(self).expect("image request is always serializable");
+
+        format!(
+            "curl -X POST '{}{}' \\\n  \
+             -H \"Authorization: Bearer $DIFFUSION_API_KEY\" \\\n  \
+             -H 'Accept: application/json' \\\n  \
+             -H 'Content-Type: application/json' \\\n  \
+             -d '{}'",
+            base_url.trim_end_matches('/'),
+            IMAGE_PATH,
+            body.replace('\'', "'\\''"),
+        )
+    }
 }
 
-impl TryFrom<String> for ImageSize {
-    type Error = DiffusionError;
+/// Implements `Display`, `FromStr`, `TryFrom<String>`, `as_str()`, `all()` and, with the
+/// `clap` feature, `clap::ValueEnum` for an enum, all driven by one list of each variant
+/// and its name so they can't drift apart. A name can be followed by `| "alias"` for an
+/// older spelling that clap still accepts on the command line.
+macro_rules! named_enum {
+    ($name:ident, $error:expr, { $($variant:ident => $value:literal $(| $alias:literal)?),+ $(,)? }) => {
+        impl $name {
+            /// The name of the variant as used by the API
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $value,)+
+                }
+            }
+
+            /// Every variant, in the order the API lists them
+            pub fn all() -> &'static [Self] {
+                &[$(Self::$variant),+]
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.as_str())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = DiffusionError;
 
-    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
-        match value.as_str() {
-            "small" => Ok(Self::Small),
-            "medium" => Ok(Self::Medium),
-            "large" => Ok(Self::Large),
-            _ => Err(DiffusionError::InvalidSize),
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                match s {
+                    $($value => Ok(Self::$variant),)+
+                    _ => Err($error),
+                }
+            }
         }
+
+        impl TryFrom<String> for $name {
+            type Error = DiffusionError;
+
+            fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+                value.parse()
+            }
+        }
+
+        #[cfg(feature = "clap")]
+        impl clap::ValueEnum for $name {
+            fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+                match self {
+                    $(Self::$variant => Some(clap::builder::PossibleValue::new($value)$(.alias($alias))?),)+
+                }
+            }
+
+            fn value_variants<'a>() -> &'a [Self] {
+                Self::all()
+            }
+        }
+    };
+}
+
+/// The available steps provided through the API
+#[derive(Debug, Serialize_repr, Deserialize_repr, Clone, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ImageSteps {
+    Fifty = 50,
+    OneHundred = 100,
+    OneHundredFifty = 150,
+    TwoHundred = 200,
+}
+
+named_enum!(ImageSteps, DiffusionError::InvalidStepAmount, {
+    Fifty => "50",
+    OneHundred => "100",
+    OneHundredFifty => "150",
+    TwoHundred => "200",
+});
+
+impl TryFrom<u16> for ImageSteps {
+    type Error = DiffusionError;
+
+    fn try_from(value: u16) -> std::result::Result<Self, Self::Error> {
+        value.to_string().parse()
     }
 }
 
+/// The available image models provided through the API
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageModel {
+    BeautyRealism,
+    AestheticRealism,
+    AnimeRealism,
+    AnalogRealism,
+    DreamReality,
+    StableDiffusion,
+    ToonAnimated,
+    FantasyAnimated,
+}
+
+named_enum!(ImageModel, DiffusionError::InvalidModel, {
+    BeautyRealism => "beauty_realism" | "beauty-realism",
+    AestheticRealism => "aesthetic_realism" | "aesthetic-realism",
+    AnimeRealism => "anime_realism" | "anime-realism",
+    AnalogRealism => "analog_realism" | "analog-realism",
+    DreamReality => "dream_reality" | "dream-reality",
+    StableDiffusion => "stable_diffusion" | "stable-diffusion",
+    ToonAnimated => "toon_animated" | "toon-animated",
+    FantasyAnimated => "fantasy_animated" | "fantasy-animated",
+});
+
+/// The available image sizes provided through the API
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSize {
+    Small,
+    Medium,
+    Large,
+}
+
+named_enum!(ImageSize, DiffusionError::InvalidSize, {
+    Small => "small",
+    Medium => "medium",
+    Large => "large",
+});
+
 /// The available iamge orientations provided through the API
-#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum ImageOrientation {
     Square,
@@ -357,28 +1868,11 @@ pub enum ImageOrientation {
     Portrait,
 }
 
-impl Display for ImageOrientation {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Square => write!(f, "square"),
-            Self::Landscape => write!(f, "landscape"),
-            Self::Portrait => write!(f, "portrait"),
-        }
-    }
-}
-
-impl TryFrom<String> for ImageOrientation {
-    type Error = DiffusionError;
-
-    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
-        match value.as_str() {
-            "square" => Ok(Self::Square),
-            "landscape" => Ok(Self::Landscape),
-            "portrait" => Ok(Self::Portrait),
-            _ => Err(DiffusionError::InvalidOrientation),
-        }
-    }
-}
+named_enum!(ImageOrientation, DiffusionError::InvalidOrientation, {
+    Square => "square",
+    Landscape => "landscape",
+    Portrait => "portrait",
+});
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct TokenBody {
@@ -387,21 +1881,99 @@ struct TokenBody {
 
 impl From<ImageToken> for TokenBody {
     fn from(value: ImageToken) -> Self {
-        Self { token: value.0 }
+        Self { token: value.token }
     }
 }
 
+/// The number of bits that differ between two
+/// [`perceptual_hash()`](DiffusionImage::perceptual_hash) values. Small distances
+/// (roughly 10 or fewer) usually mean the images are near-duplicates.
+#[cfg(feature = "image")]
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// The prompts in a prompt file, one per line, skipping blank lines and `#` comments
+fn prompt_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
 /// A token returned from the API that is used to check
 /// the status of the image and get the image when completed
-#[derive(Clone)]
-pub struct ImageToken(String);
+#[derive(Debug, Clone)]
+pub struct ImageToken {
+    token: String,
+    issued_at: Option<Instant>,
+    expires_at: Option<Instant>,
+}
+
+impl ImageToken {
+    /// Whether the token is older than the client's configured maximum token age, going
+    /// by the system clock. Tokens without a maximum age never expire. For a client with
+    /// a custom [`Clock`], use [`DiffusionClient::is_token_expired()`] instead.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Instant::now())
+    }
+
+    fn is_expired_at(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| now >= t)
+    }
+
+    /// The token as returned by the API, suitable for persisting and resuming later
+    pub fn as_str(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Display for ImageToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.token)
+    }
+}
+
+impl From<String> for ImageToken {
+    fn from(value: String) -> Self {
+        Self {
+            token: value,
+            issued_at: None,
+            expires_at: None,
+        }
+    }
+}
+
+impl From<&str> for ImageToken {
+    fn from(value: &str) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl FromStr for ImageToken {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(s.into())
+    }
+}
 
 impl From<TokenBody> for ImageToken {
     fn from(value: TokenBody) -> Self {
-        Self(value.token)
+        value.token.into()
     }
 }
 
+/// The status of an image as reported by the API
+#[derive(Debug, Clone)]
+pub enum ImageStatus {
+    /// The image is still being generated
+    Pending,
+    /// The image is complete
+    Complete(DiffusionImage),
+}
+
 #[derive(Deserialize, Clone)]
 struct StatusResponse {
     data: DiffusionImage,
@@ -409,14 +1981,873 @@ struct StatusResponse {
 
 /// The image response returned from the API when the
 /// image is complete
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Clone)]
 pub struct DiffusionImage {
     pub id: u64,
     pub steps: ImageSteps,
     pub size: ImageSize,
     pub model: ImageModel,
-    pub credits_used: u64,
+    #[serde(alias = "creditsUsed")]
+    pub credits_used: f64,
+    #[serde(alias = "createdAt")]
     pub created_at: String,
+    #[serde(alias = "updatedAt")]
     pub updated_at: String,
     pub raw: String,
+    /// The exact response body the image was parsed from, when the client was built with
+    /// [`capture_raw_responses()`](DiffusionClientBuilder::capture_raw_responses)
+    #[serde(skip)]
+    pub raw_response: Option<Vec<u8>>,
+}
+
+impl std::fmt::Debug for DiffusionImage {
+    /// Shows the sizes of the image data and raw response instead of their contents, which
+    /// can be megabytes long
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiffusionImage")
+            .field("id", &self.id)
+            .field("steps", &self.steps)
+            .field("size", &self.size)
+            .field("model", &self.model)
+            .field("credits_used", &self.credits_used)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .field("raw", &format_args!("<{} bytes base64>", self.raw.len()))
+            .field(
+                "raw_response",
+                &self
+                    .raw_response
+                    .as_ref()
+                    .map(|body| format!("<{} bytes>", body.len())),
+            )
+            .finish()
+    }
+}
+
+/// The formats an image can be re-encoded to locally
+#[cfg(feature = "image")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+}
+
+/// The metadata of a completed image, without the image data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiffusionImageMeta {
+    pub id: u64,
+    pub steps: ImageSteps,
+    pub size: ImageSize,
+    pub model: ImageModel,
+    pub credits_used: f64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// The MIME type of the image format whose magic bytes start the given data
+fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        _ => None,
+    }
+}
+
+/// The base64 variants an image can be re-encoded in
+#[derive(Debug, Clone, Copy)]
+pub enum Base64Variant {
+    /// The standard alphabet with padding
+    Standard,
+    /// The standard alphabet without padding
+    StandardNoPad,
+    /// The URL-safe alphabet, using `-` and `_` instead of `+` and `/`, with padding
+    UrlSafe,
+    /// The URL-safe alphabet without padding
+    UrlSafeNoPad,
+}
+
+impl DiffusionImage {
+    /// The base64 payload of the image, without any `data:` URL prefix
+    fn payload(&self) -> &str {
+        self.raw
+            .split_once(',')
+            .map_or(self.raw.as_str(), |(_, payload)| payload)
+    }
+
+    /// Decode the base64 image data into the raw image bytes
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        Ok(LENIENT_BASE64.decode(self.payload())?)
+    }
+
+    /// Decode the base64 image data, rejecting any encoding that isn't canonical (such as
+    /// missing padding or non-zero trailing bits) with [`DiffusionError::NonCanonicalBase64`].
+    /// [`decode()`](DiffusionImage::decode) accepts these, but then several encodings map to
+    /// the same bytes, which matters when the data feeds a content-addressed store.
+    pub fn decode_strict(&self) -> Result<Vec<u8>> {
+        general_purpose::STANDARD
+            .decode(self.payload())
+            .map_err(|e| match self.decode() {
+                Ok(_) => DiffusionError::NonCanonicalBase64,
+                Err(_) => e.into(),
+            })
+    }
+
+    /// Decode the base64 image data a piece at a time, writing the bytes to the given writer
+    /// without holding the whole decoded image in memory
+    pub async fn decode_to_writer<W: AsyncWrite + Unpin>(&self, mut writer: W) -> Result<()> {
+        for chunk in self.payload().as_bytes().chunks(DECODE_CHUNK_LEN) {
+            writer.write_all(&LENIENT_BASE64.decode(chunk)?).await?;
+        }
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Decode the image and re-encode it locally in another format, avoiding the cost of a
+    /// new generation. `quality` ranges from 1 to 100 and only applies to JPEG, defaulting
+    /// to 80. PNG and WebP are encoded losslessly, and JPEG drops any transparency.
+    #[cfg(feature = "image")]
+    pub fn reencode(&self, format: ImageFormat, quality: Option<u8>) -> Result<Vec<u8>> {
+        use image::codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder};
+
+        if quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(DiffusionError::InvalidQuality);
+        }
+        if quality.is_some() && format != ImageFormat::Jpeg {
+            return Err(DiffusionError::QualityWithLosslessFormat);
+        }
+
+        let image = image::load_from_memory(&self.decode()?)?;
+        let mut out = Vec::new();
+        match format {
+            ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut out))?,
+            ImageFormat::Jpeg => {
+                let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+                image
+                    .to_rgb8()
+                    .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?
+            }
+            ImageFormat::WebP => image
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut out))?,
+        }
+
+        Ok(out)
+    }
+
+    /// Decode the image and re-encode it in the same format without any metadata, such as
+    /// EXIF data or PNG text chunks, so nothing beyond the pixels is shared. PNG and WebP
+    /// are re-encoded losslessly and JPEG at maximum quality, though the bytes (and so any
+    /// content hash) will differ from [`decode()`](Self::decode). Other formats come out
+    /// as PNG.
+    #[cfg(feature = "image")]
+    pub fn decode_stripped(&self) -> Result<Vec<u8>> {
+        match self.mime_type()? {
+            "image/jpeg" => self.reencode(ImageFormat::Jpeg, Some(100)),
+            "image/webp" => self.reencode(ImageFormat::WebP, None),
+            _ => self.reencode(ImageFormat::Png, None),
+        }
+    }
+
+    /// Decode the image and re-encode it as a PNG with the generation parameters written to
+    /// a `parameters` tEXt chunk, in the style of other Stable Diffusion tools, so they can
+    /// be read back from the file alone. The API doesn't return the prompt with the image,
+    /// so only the model, steps, size, and id are recorded.
+    #[cfg(feature = "image")]
+    pub fn to_png_with_metadata(&self) -> Result<Vec<u8>> {
+        let image = image::load_from_memory(&self.decode()?)?.to_rgba8();
+        let parameters = format!(
+            "Steps: {}, Size: {}, Model: {}, Id: {}",
+            self.steps, self.size, self.model, self.id
+        );
+
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, image.width(), image.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .add_text_chunk("parameters".to_string(), parameters)
+            .map_err(std::io::Error::from)?;
+        let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+        writer
+            .write_image_data(image.as_raw())
+            .map_err(std::io::Error::from)?;
+        writer.finish().map_err(std::io::Error::from)?;
+
+        Ok(out)
+    }
+
+    /// A 64-bit difference hash (dHash) of the image. Visually similar images produce
+    /// hashes that differ in only a few bits, so near-duplicates can be found by comparing
+    /// hashes with [`hamming_distance()`].
+    #[cfg(feature = "image")]
+    pub fn perceptual_hash(&self) -> Result<u64> {
+        let image = image::load_from_memory(&self.decode()?)?
+            .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+            .to_luma8();
+
+        let mut hash = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                let brighter = image.get_pixel(x, y)[0] < image.get_pixel(x + 1, y)[0];
+                hash = (hash << 1) | u64::from(brighter);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// A short one-line description of the image for logging, like
+    /// `#12345 beauty_realism 100steps small 8credits`. The image data is left out.
+    pub fn summary(&self) -> String {
+        format!(
+            "#{} {} {}steps {} {}credits",
+            self.id, self.model, self.steps, self.size, self.credits_used
+        )
+    }
+
+    /// Everything about the image except the image data itself
+    pub fn meta(&self) -> DiffusionImageMeta {
+        DiffusionImageMeta {
+            id: self.id,
+            steps: self.steps.clone(),
+            size: self.size.clone(),
+            model: self.model.clone(),
+            credits_used: self.credits_used,
+            created_at: self.created_at.clone(),
+            updated_at: self.updated_at.clone(),
+        }
+    }
+
+    /// Decode the base64 image data into [`bytes::Bytes`], which can be cheaply cloned and
+    /// shared between several consumers of the image without copying it
+    #[cfg(feature = "bytes")]
+    pub fn decode_bytes(&self) -> Result<bytes::Bytes> {
+        Ok(self.decode()?.into())
+    }
+
+    /// Decode the image into a multipart form part with the given file name and the
+    /// detected MIME type, ready to forward in a multipart upload
+    #[cfg(feature = "multipart")]
+    pub fn into_multipart_part(&self, name: &str) -> Result<reqwest::multipart::Part> {
+        Ok(reqwest::multipart::Part::bytes(self.decode()?)
+            .file_name(name.to_string())
+            .mime_str(self.mime_type()?)?)
+    }
+
+    /// Re-encode the image data as base64 using the given variant, without any `data:` URL
+    /// prefix. The `raw` field itself is left in the encoding the API returned.
+    pub fn base64(&self, variant: Base64Variant) -> Result<String> {
+        let bytes = self.decode()?;
+
+        Ok(match variant {
+            Base64Variant::Standard => general_purpose::STANDARD.encode(bytes),
+            Base64Variant::StandardNoPad => general_purpose::STANDARD_NO_PAD.encode(bytes),
+            Base64Variant::UrlSafe => general_purpose::URL_SAFE.encode(bytes),
+            Base64Variant::UrlSafeNoPad => general_purpose::URL_SAFE_NO_PAD.encode(bytes),
+        })
+    }
+
+    /// Detect the MIME type of the image by sniffing the magic bytes at the start
+    /// of the decoded data. Only the first few bytes are decoded.
+    pub fn mime_type(&self) -> Result<&'static str> {
+        let payload = self.payload();
+        let header = LENIENT_BASE64.decode(payload.get(..16).unwrap_or(payload))?;
+
+        sniff_mime_type(&header).ok_or(DiffusionError::UnknownImageFormat)
+    }
+
+    /// Check that the image data is a complete, well-formed image before trusting it. This
+    /// decodes the base64 and checks the format's magic bytes along with its end marker
+    /// (or declared length), which catches truncated or garbled responses without fully
+    /// decoding the image.
+    pub fn verify(&self) -> Result<()> {
+        let bytes = self.decode()?;
+
+        let complete = match sniff_mime_type(&bytes) {
+            Some("image/png") => {
+                bytes.get(12..16) == Some(b"IHDR")
+                    && bytes
+                        .ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82])
+            }
+            Some("image/jpeg") => bytes.ends_with(&[0xFF, 0xD9]),
+            Some("image/webp") => {
+                let declared = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+                declared as usize + 8 == bytes.len()
+            }
+            Some("image/gif") => bytes.ends_with(&[0x3B]),
+            _ => false,
+        };
+
+        if complete {
+            Ok(())
+        } else {
+            Err(DiffusionError::CorruptImage)
+        }
+    }
+
+    /// A `data:<mime>;base64,<payload>` URL ready to be used as the `src` of an HTML
+    /// `<img>`. If the API already returned a data URL it is used as-is, otherwise the
+    /// prefix is built from the detected MIME type.
+    pub fn html_data_url(&self) -> Result<Cow<'_, str>> {
+        if self.raw.starts_with("data:") && self.raw.contains(";base64,") {
+            return Ok(Cow::Borrowed(&self.raw));
+        }
+
+        Ok(Cow::Owned(format!(
+            "data:{};base64,{}",
+            self.mime_type()?,
+            self.payload()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::AbortHandle;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A clock that only moves when slept on, recording every sleep
+    #[derive(Clone)]
+    struct ManualClock {
+        start: Instant,
+        sleeps: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl ManualClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                sleeps: Default::default(),
+            }
+        }
+
+        fn sleeps(&self) -> Vec<Duration> {
+            self.sleeps.lock().unwrap().clone()
+        }
+
+        fn elapsed(&self) -> Duration {
+            self.sleeps().iter().sum()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.sleeps.lock().unwrap().push(duration);
+            Box::pin(futures::future::ready(()))
+        }
+    }
+
+    /// Serve every request with the same raw HTTP response, returning the base URL and
+    /// a count of the requests served
+    fn serve(response: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                served.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn test_client(url: String, clock: &ManualClock) -> DiffusionClient {
+        DiffusionClient::builder("key".to_string())
+            .base_url(url)
+            .clock(clock.clone())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn patient_polling_backs_off_until_the_deadline() {
+        let (url, requests) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let client = test_client(url, &clock);
+
+        let result = client
+            .check_and_wait_with(
+                "token".into(),
+                Some(Duration::from_secs(120)),
+                PollConfig::patient(),
+            )
+            .await;
+
+        assert!(matches!(result, Err(DiffusionError::TimeExpired)));
+        assert_eq!(requests.load(Ordering::SeqCst), 6);
+        assert_eq!(
+            clock.sleeps(),
+            [10.0, 15.0, 22.5, 33.75, 50.625].map(Duration::from_secs_f64)
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_deadline() {
+        let (url, _) = serve("HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let client = test_client(url, &clock);
+        let config =
+            PollConfig::new(Duration::from_secs(10)).update_retries(10, Duration::from_secs(1));
+
+        let result = client
+            .check_and_wait_with("token".into(), Some(Duration::from_secs(60)), config)
+            .await;
+
+        assert!(matches!(result, Err(DiffusionError::TimeExpired)));
+        // unbounded, the first poll alone would spend 1023s retrying
+        assert_eq!(clock.elapsed(), Duration::from_secs(66));
+    }
+
+    #[tokio::test]
+    async fn cut_off_responses_are_retried_by_default() {
+        let (url, requests) =
+            serve("HTTP/1.1 201 Created\r\nContent-Length: 100\r\nConnection: close\r\n\r\n{");
+        let clock = ManualClock::new();
+        let client = test_client(url, &clock);
+
+        let result = client
+            .poll(&"token".into(), &PollConfig::default(), None)
+            .await;
+
+        assert!(matches!(result, Err(DiffusionError::IncompleteResponse)));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            clock.sleeps(),
+            [Duration::from_secs(1), Duration::from_secs(2)]
+        );
+    }
+
+    const TOKEN_RESPONSE: &str =
+        "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\n{\"token\":\"abc\"}";
+
+    #[tokio::test]
+    async fn huge_token_ages_never_expire() {
+        let (url, _) = serve(TOKEN_RESPONSE);
+        let clock = ManualClock::new();
+        let client = DiffusionClient::builder("key".to_string())
+            .base_url(url.clone())
+            .clock(clock.clone())
+            .max_token_age(Duration::MAX)
+            .build()
+            .unwrap();
+
+        let token = client
+            .request_image(ImageRequest::new("a cat"))
+            .await
+            .unwrap();
+        assert_eq!(token.as_str(), "abc");
+        assert!(!client.is_token_expired(&token));
+
+        let client = DiffusionClient::from_config(Config {
+            api_key: "key".to_string(),
+            base_url: Some(url),
+            max_token_age_secs: Some(1e19),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(client
+            .request_image(ImageRequest::new("a cat"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn dead_tokens_are_removed_from_the_store() {
+        let (url, _) = serve("HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let store = Arc::new(MemoryTokenStore::default());
+        let client = DiffusionClient::builder("key".to_string())
+            .base_url(url)
+            .clock(clock.clone())
+            .token_store(store.clone())
+            .build()
+            .unwrap();
+        let request = ImageRequest::new("a cat");
+
+        let unknown = ImageToken::from("unknown");
+        store.save(&unknown, &request).unwrap();
+        let result = client.check_and_wait(unknown, None).await;
+        assert!(matches!(result, Err(DiffusionError::TokenNotFound)));
+        assert!(store.load_all().unwrap().is_empty());
+
+        let mut expired = ImageToken::from("expired");
+        expired.expires_at = Some(clock.now());
+        store.save(&expired, &request).unwrap();
+        let result = client.check_and_wait(expired, None).await;
+        assert!(matches!(result, Err(DiffusionError::TokenExpired)));
+        assert!(store.load_all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_stop_waiting_immediately() {
+        for response in [
+            "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 410 Gone\r\nConnection: close\r\n\r\n",
+        ] {
+            let (url, requests) = serve(response);
+            let clock = ManualClock::new();
+            let client = test_client(url, &clock);
+
+            let result = client.check_and_wait("token".into(), None).await;
+
+            assert!(matches!(result, Err(DiffusionError::TokenNotFound)));
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+            assert!(clock.sleeps().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_and_unknown_tokens_are_told_apart() {
+        let clock = ManualClock::new();
+
+        let (url, _) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let result = test_client(url, &clock).check_status("token".into()).await;
+        assert!(matches!(result, Err(DiffusionError::ImageStatusNotReady)));
+
+        let (url, _) = serve("HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n");
+        let result = test_client(url, &clock).check_status("token".into()).await;
+        assert!(matches!(result, Err(DiffusionError::TokenNotFound)));
+    }
+
+    #[tokio::test]
+    async fn initial_delay_stops_at_the_deadline() {
+        for (delay, jitter) in [
+            (Duration::from_secs(60), Duration::ZERO),
+            (Duration::MAX, Duration::MAX),
+        ] {
+            let (url, requests) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+            let clock = ManualClock::new();
+            let client = test_client(url, &clock);
+            let config = PollConfig::default().update_initial_delay(delay, jitter);
+
+            let result = client
+                .check_and_wait_with("token".into(), Some(Duration::from_secs(10)), config)
+                .await;
+
+            assert!(matches!(result, Err(DiffusionError::TimeExpired)));
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+            assert_eq!(clock.sleeps(), [Duration::from_secs(10)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_is_sent_once_per_poll() {
+        let (url, requests) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let client = test_client(url, &clock);
+        let (tx, rx) = mpsc::channel(16);
+        let options = WaitOptions::new()
+            .update_max_wait(Duration::from_secs(20))
+            .update_poll_config(PollConfig::new(Duration::from_secs(7)))
+            .update_progress(tx);
+
+        let result = client.check_and_wait_full("token".into(), options).await;
+
+        assert!(matches!(result, Err(DiffusionError::TimeExpired)));
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        let progress: Vec<_> = rx.map(|p| (p.polls, p.elapsed.as_secs())).collect().await;
+        assert_eq!(progress, [(1, 0), (2, 7), (3, 14), (4, 21)]);
+    }
+
+    #[tokio::test]
+    async fn wait_options_default_to_the_client_schedule() {
+        let (url, requests) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let client = DiffusionClient::builder("key".to_string())
+            .base_url(url)
+            .clock(clock.clone())
+            .poll_config(PollConfig::new(Duration::from_secs(7)))
+            .build()
+            .unwrap();
+        let options = WaitOptions::new().update_max_wait(Duration::from_secs(20));
+
+        let result = client.check_and_wait_full("token".into(), options).await;
+
+        assert!(matches!(result, Err(DiffusionError::TimeExpired)));
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert_eq!(clock.sleeps(), [Duration::from_secs(7); 3]);
+    }
+
+    #[tokio::test]
+    async fn cancelling_gives_the_token_back() {
+        let (url, requests) = serve("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        let clock = ManualClock::new();
+        let client = test_client(url, &clock);
+        let (handle, registration) = AbortHandle::new_pair();
+        handle.abort();
+
+        let result = client
+            .check_and_wait_cancellable("abc".into(), None, registration)
+            .await;
+
+        match result {
+            Err(DiffusionError::Cancelled(token)) => assert_eq!(token.as_str(), "abc"),
+            other => panic!("expected Cancelled, got {:?}", other),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn token_expiry_follows_the_client_clock() {
+        let clock = ManualClock::new();
+        let client = DiffusionClient::builder("key".to_string())
+            .clock(clock.clone())
+            .max_token_age(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let mut token = ImageToken::from("token");
+        token.expires_at = Some(clock.now() + Duration::from_secs(60));
+
+        assert!(!client.is_token_expired(&token));
+        clock.sleep(Duration::from_secs(60)).await;
+        assert!(client.is_token_expired(&token));
+    }
+
+    #[test]
+    fn enums_round_trip_through_their_names() {
+        for steps in ImageSteps::all() {
+            assert_eq!(steps.to_string().parse::<ImageSteps>().unwrap(), *steps);
+            assert_eq!(ImageSteps::try_from(steps.to_string()).unwrap(), *steps);
+        }
+        for model in ImageModel::all() {
+            assert_eq!(model.to_string().parse::<ImageModel>().unwrap(), *model);
+            assert_eq!(ImageModel::try_from(model.to_string()).unwrap(), *model);
+        }
+        for size in ImageSize::all() {
+            assert_eq!(size.to_string().parse::<ImageSize>().unwrap(), *size);
+            assert_eq!(ImageSize::try_from(size.to_string()).unwrap(), *size);
+        }
+        for orientation in ImageOrientation::all() {
+            assert_eq!(
+                orientation.to_string().parse::<ImageOrientation>().unwrap(),
+                *orientation
+            );
+            assert_eq!(
+                ImageOrientation::try_from(orientation.to_string()).unwrap(),
+                *orientation
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_enum_names_give_the_matching_error() {
+        for name in ["", "0", "75", "fifty", "small"] {
+            assert!(matches!(
+                name.parse::<ImageSteps>(),
+                Err(DiffusionError::InvalidStepAmount)
+            ));
+        }
+        assert!(matches!(
+            ImageSteps::try_from(75),
+            Err(DiffusionError::InvalidStepAmount)
+        ));
+        for name in ["", "BeautyRealism", "beauty realism", "50"] {
+            assert!(matches!(
+                name.parse::<ImageModel>(),
+                Err(DiffusionError::InvalidModel)
+            ));
+        }
+        for name in ["", "Small", "huge", "square"] {
+            assert!(matches!(
+                name.parse::<ImageSize>(),
+                Err(DiffusionError::InvalidSize)
+            ));
+        }
+        for name in ["", "Square", "diagonal", "small"] {
+            assert!(matches!(
+                ImageOrientation::try_from(name.to_string()),
+                Err(DiffusionError::InvalidOrientation)
+            ));
+        }
+    }
+
+    fn parse_status(credits: &str) -> DiffusionImage {
+        let json = format!(
+            r#"{{"data": {{"id": 1, "steps": 50, "size": "small", "model": "beauty_realism",
+                "credits_used": {}, "created_at": "c", "updated_at": "u", "raw": ""}}}}"#,
+            credits
+        );
+        serde_json::from_str::<StatusResponse>(&json).unwrap().data
+    }
+
+    fn image_with_raw(raw: &str) -> DiffusionImage {
+        DiffusionImage {
+            raw: raw.to_string(),
+            ..parse_status("1")
+        }
+    }
+
+    #[test]
+    fn debug_output_leaves_out_the_image_data() {
+        let raw = "A".repeat(4096);
+        let debug = format!("{:?}", image_with_raw(&raw));
+
+        assert!(!debug.contains(&raw));
+        assert!(debug.contains("<4096 bytes base64>"));
+    }
+
+    #[test]
+    fn url_safe_base64_avoids_plus_and_slash() {
+        let bytes = [0xfb, 0xff, 0xbf, 0xfb, 0xff];
+        let standard = general_purpose::STANDARD.encode(bytes);
+        assert!(standard.contains('+') && standard.contains('/'));
+        let image = image_with_raw(&standard);
+
+        for variant in [Base64Variant::UrlSafe, Base64Variant::UrlSafeNoPad] {
+            let encoded = image.base64(variant).unwrap();
+            assert!(!encoded.contains('+') && !encoded.contains('/'));
+            assert_eq!(
+                general_purpose::URL_SAFE_NO_PAD
+                    .decode(encoded.trim_end_matches('='))
+                    .unwrap(),
+                bytes
+            );
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn jpeg_quality_outside_1_to_100_is_rejected() {
+        let image = image_with_raw("");
+
+        for quality in [0, 101] {
+            let result = image.reencode(ImageFormat::Jpeg, Some(quality));
+            assert!(matches!(result, Err(DiffusionError::InvalidQuality)));
+        }
+    }
+
+    #[test]
+    fn html_data_urls_have_exactly_one_prefix() {
+        const PNG: &str = "iVBORw0KGgoAAAANSUhEUg==";
+        let expected = format!("data:image/png;base64,{}", PNG);
+
+        assert_eq!(image_with_raw(PNG).html_data_url().unwrap(), expected);
+        assert_eq!(image_with_raw(&expected).html_data_url().unwrap(), expected);
+        assert_eq!(
+            image_with_raw(&format!("data:image/png,{}", PNG))
+                .html_data_url()
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn prompt_files_skip_blank_lines_and_comments() {
+        let text = "a red fox\n\n# a comment\n  \n  a blue whale  \n   # indented\r\na green frog";
+
+        assert_eq!(
+            prompt_lines(text),
+            ["a red fox", "a blue whale", "a green frog"]
+        );
+    }
+
+    #[test]
+    fn credits_used_accepts_integers_and_floats() {
+        assert_eq!(parse_status("8").credits_used, 8.0);
+        assert_eq!(parse_status("0.5").credits_used, 0.5);
+    }
+
+    #[test]
+    fn status_accepts_camel_case_fields() {
+        let json = r#"{"data": {"id": 1, "steps": 50, "size": "small", "model": "beauty_realism",
+            "creditsUsed": 2.5, "createdAt": "c", "updatedAt": "u", "raw": ""}}"#;
+        let image = serde_json::from_str::<StatusResponse>(json).unwrap().data;
+
+        assert_eq!(image.credits_used, 2.5);
+        assert_eq!(image.created_at, "c");
+        assert_eq!(image.updated_at, "u");
+    }
+
+    #[test]
+    fn huge_backoff_caps_at_the_max_interval() {
+        let max = Duration::from_secs(60);
+        for backoff in [1e20, f64::MAX, f64::INFINITY] {
+            let config = PollConfig::new(Duration::from_secs(5))
+                .update_backoff(backoff)
+                .update_max_interval(max);
+            assert_eq!(config.next_interval(Duration::from_secs(5)), max);
+            assert_eq!(config.next_interval(max), max);
+        }
+    }
+
+    #[test]
+    fn config_debug_redacts_the_api_key() {
+        let config = Config {
+            api_key: "super-secret-key".to_string(),
+            ..Default::default()
+        };
+        assert!(!format!("{:?}", config).contains("super-secret-key"));
+    }
+
+    #[test]
+    fn config_problems_are_reported_together() {
+        let config = Config {
+            api_key: " ".to_string(),
+            poll_interval_secs: Some(0.0),
+            poll_backoff: Some(f64::INFINITY),
+            ..Default::default()
+        };
+        match DiffusionClient::from_config(config) {
+            Err(DiffusionError::InvalidConfig(problems)) => assert_eq!(problems.len(), 3),
+            _ => panic!("expected an invalid config"),
+        }
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn clap_values_match_the_enum_names() {
+        use clap::ValueEnum;
+
+        fn check<T: ValueEnum + Display + FromStr + PartialEq + std::fmt::Debug>()
+        where
+            T::Err: std::fmt::Debug,
+        {
+            for value in T::value_variants() {
+                let possible = value.to_possible_value().unwrap();
+                assert_eq!(possible.get_name(), value.to_string());
+                assert_eq!(possible.get_name().parse::<T>().unwrap(), *value);
+                assert_eq!(
+                    <T as ValueEnum>::from_str(possible.get_name(), false).unwrap(),
+                    *value
+                );
+            }
+        }
+
+        check::<ImageSteps>();
+        check::<ImageModel>();
+        check::<ImageSize>();
+        check::<ImageOrientation>();
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn clap_accepts_the_old_kebab_case_model_names() {
+        use clap::ValueEnum;
+
+        for model in ImageModel::all() {
+            let kebab = model.as_str().replace('_', "-");
+            assert_eq!(
+                <ImageModel as ValueEnum>::from_str(&kebab, false).unwrap(),
+                *model
+            );
+        }
+    }
 }
@@ -1,8 +1,8 @@
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
-use anyhow::{anyhow, Result};
-use base64::prelude::*;
+use anyhow::Result;
 use clap::Parser;
+use serde_json::json;
 use sha2::{Digest, Sha256};
 use tokio::fs;
 
@@ -17,8 +17,17 @@ struct Args {
     api_key: String,
 
     /// The prompt for the image
-    #[arg(short, long)]
-    prompt: String,
+    #[arg(short, long, required_unless_present = "prompt_file")]
+    prompt: Option<String>,
+
+    /// A file with one prompt per line to generate an image for each of. Blank lines and
+    /// lines starting with `#` are skipped
+    #[arg(long, conflicts_with = "prompt")]
+    prompt_file: Option<String>,
+
+    /// The most images to generate at once when using a prompt file
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
 
     /// The negative prompt for the image
     #[arg(short, long)]
@@ -40,9 +49,14 @@ struct Args {
     #[arg(short, long, value_enum, default_value_t = ImageOrientation::Square)]
     orientation: ImageOrientation,
 
-    /// The file to output the image to
+    /// The file to output the image to. With a prompt file, each prompt's number
+    /// is added to the name
     #[arg(long)]
     out: Option<String>,
+
+    /// Save the request parameters and image metadata to a JSON file next to the image
+    #[arg(long)]
+    save_params: bool,
 }
 
 #[tokio::main]
@@ -50,30 +64,90 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     let client = DiffusionClient::new(args.api_key)?;
 
-    let mut request = ImageRequest::new(args.prompt)
-        .update_steps(args.steps.try_into()?)
-        .update_model(args.model.try_into()?)
-        .update_size(args.size.try_into()?)
-        .update_orientation(args.orientation.try_into()?);
-    if let Some(negative) = args.negative {
-        request = request.update_negative_prompt(negative);
-    }
+    let build_request = |prompt: String| {
+        let mut request = ImageRequest::new(prompt)
+            .update_steps(args.steps.clone())
+            .update_model(args.model.clone())
+            .update_size(args.size.clone())
+            .update_orientation(args.orientation.clone());
+        if let Some(negative) = &args.negative {
+            request = request.update_negative_prompt(negative.clone());
+        }
+        request
+    };
 
-    let token = client.request_image(request).await?;
     // wait for up to five minutes
-    let image = client
-        .check_and_wait(token, Some(Duration::from_secs(300)))
+    let max_wait = Some(Duration::from_secs(300));
+
+    let Some(prompt_file) = &args.prompt_file else {
+        let request = build_request(args.prompt.clone().unwrap_or_default());
+        let image = client.generate(request.clone(), max_wait).await?;
+        save(
+            &image,
+            args.out.clone(),
+            args.save_params.then_some(&request),
+        )
         .await?;
 
+        return Ok(());
+    };
+
+    let prompts = fs::read_to_string(prompt_file).await?;
+    let results = client
+        .generate_from_lines(
+            &prompts,
+            build_request(String::new()),
+            max_wait,
+            args.concurrency,
+        )
+        .await;
+    let total = results.len();
+    let mut failed = 0;
+    for (i, (prompt, result)) in results.into_iter().enumerate() {
+        let image = match result {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("prompt {} ({:?}) failed: {}", i + 1, prompt, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        // number the outputs so a run over the same file always names them the same way
+        let filename = args.out.as_ref().map(|out| {
+            let out = Path::new(out);
+            let stem = out.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = out.extension().unwrap_or("png".as_ref()).to_string_lossy();
+            out.with_file_name(format!("{}-{}.{}", stem, i + 1, ext))
+                .to_string_lossy()
+                .into_owned()
+        });
+        let request = build_request(prompt.clone());
+        // keep going so one bad write doesn't lose the images already paid for
+        if let Err(e) = save(&image, filename, args.save_params.then_some(&request)).await {
+            eprintln!("prompt {} ({:?}) could not be saved: {}", i + 1, prompt, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} prompts failed", failed, total);
+    }
+
+    Ok(())
+}
+
+/// Write the image to the given file, or one named after its hash, along with a JSON
+/// sidecar of the parameters if requested
+async fn save(
+    image: &DiffusionImage,
+    out: Option<String>,
+    params: Option<&ImageRequest>,
+) -> Result<()> {
     // process and save image
-    let contents = image
-        .raw
-        .split(",")
-        .last()
-        .ok_or(anyhow!("invalid raw image data"))?;
-    let binary = BASE64_STANDARD.decode(contents)?;
-
-    let filename = if let Some(filename) = args.out {
+    let binary = image.decode()?;
+
+    let filename = if let Some(filename) = out {
         filename
     } else {
         let hash = Sha256::digest(&binary);
@@ -84,5 +158,16 @@ async fn main() -> Result<()> {
 
     println!("image written to {}", filename);
 
+    if let Some(request) = params {
+        let sidecar = Path::new(&filename).with_extension("json");
+        let contents = serde_json::to_vec_pretty(&json!({
+            "request": request,
+            "image": image.meta(),
+        }))?;
+        fs::write(&sidecar, contents).await?;
+
+        println!("parameters written to {}", sidecar.display());
+    }
+
     Ok(())
 }